@@ -0,0 +1,259 @@
+use crate::{
+    algebra::{change_streams_unsupported, DefinitionStorageExt, OPEN_BACKOFF, REOPEN_DELAY},
+    DefinitionSettings, ExpiryPolicy, Grant, Validation,
+};
+use futures::StreamExt;
+use handlebars::Handlebars;
+use jsonwebtoken::jwk::JwkSet;
+use mongodb::bson::{doc, Document};
+use osentities::{
+    connection_oauth_definition::ConnectionOAuthDefinition, Id, InternalError, MongoStore,
+    PicaError,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+const TEMPLATE_NAME: &str = "definition";
+
+/// A connection oauth definition together with its grant, expiry and validation
+/// settings and, when `is_full_template_enabled` is set, the definition compiled
+/// as a template.
+#[derive(Debug, Clone)]
+pub struct CachedDefinition {
+    definition: Arc<ConnectionOAuthDefinition>,
+    settings: DefinitionSettings,
+    template: Option<Arc<Handlebars<'static>>>,
+    fetched_at: Instant,
+}
+
+impl CachedDefinition {
+    fn new(
+        definition: ConnectionOAuthDefinition,
+        settings: DefinitionSettings,
+    ) -> Result<Self, PicaError> {
+        let template = if definition.is_full_template_enabled {
+            let source = serde_json::to_string_pretty(&definition).map_err(|e| {
+                InternalError::serialize_error(&e.to_string(), Some("CachedDefinition::new"))
+            })?;
+
+            let mut template = Handlebars::new();
+            template
+                .register_template_string(TEMPLATE_NAME, source)
+                .map_err(|e| {
+                    InternalError::serialize_error(&e.to_string(), Some("CachedDefinition::new"))
+                })?;
+
+            Some(Arc::new(template))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            definition: Arc::new(definition),
+            settings,
+            template,
+            fetched_at: Instant::now(),
+        })
+    }
+
+    pub fn expiry_policy(&self) -> &ExpiryPolicy {
+        &self.settings.expiry.expiry_policy
+    }
+
+    pub fn expiry_skew_in_seconds(&self) -> i64 {
+        self.settings.expiry.expiry_skew_in_seconds
+    }
+
+    pub fn validation(&self) -> Option<&Validation> {
+        self.settings.validation.as_ref()
+    }
+
+    pub fn grant(&self) -> &Grant {
+        &self.settings.grant
+    }
+
+    /// Platform the connections of this definition belong to.
+    pub fn platform(&self) -> &str {
+        &self.definition.connection_platform
+    }
+
+    /// Renders the definition against `payload`, or returns it as stored when full
+    /// templating is disabled.
+    pub fn render(&self, payload: &Value) -> Result<ConnectionOAuthDefinition, PicaError> {
+        let Some(template) = &self.template else {
+            return Ok(self.definition.as_ref().clone());
+        };
+
+        let rendered = template
+            .render(TEMPLATE_NAME, &Some(payload))
+            .map_err(|e| {
+                InternalError::serialize_error(&e.to_string(), Some("CachedDefinition::render"))
+            })?;
+
+        serde_json::from_str(&rendered).map_err(|e| {
+            InternalError::serialize_error(&e.to_string(), Some("CachedDefinition::render"))
+        })
+    }
+}
+
+/// Read-through cache of connection oauth definitions, and of the JWKS their
+/// tokens are verified against, with a time to live.
+#[derive(Debug)]
+pub struct DefinitionCache {
+    store: Arc<MongoStore<ConnectionOAuthDefinition>>,
+    ttl: Duration,
+    entries: RwLock<HashMap<Id, CachedDefinition>>,
+    jwks: RwLock<HashMap<String, (Arc<JwkSet>, Instant)>>,
+}
+
+impl DefinitionCache {
+    pub fn new(store: Arc<MongoStore<ConnectionOAuthDefinition>>, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+            jwks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn store(&self) -> &Arc<MongoStore<ConnectionOAuthDefinition>> {
+        &self.store
+    }
+
+    pub async fn get(&self, id: &Id) -> Result<Option<CachedDefinition>, PicaError> {
+        if let Some(cached) = self.cached(id) {
+            return Ok(Some(cached));
+        }
+
+        let Some(definition) = self.store.get_one(doc! { "_id": id.to_string() }).await? else {
+            return Ok(None);
+        };
+        let settings = self.store.get_settings(id).await?;
+        let cached = CachedDefinition::new(definition, settings)?;
+
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(*id, cached.clone());
+        }
+
+        Ok(Some(cached))
+    }
+
+    /// Returns the JWKS published at `uri`. `refetch` bypasses the cached copy,
+    /// which is needed once the provider rotates its signing keys.
+    pub async fn jwks(
+        &self,
+        uri: &str,
+        client: &ClientWithMiddleware,
+        refetch: bool,
+    ) -> Result<Arc<JwkSet>, PicaError> {
+        if !refetch {
+            let cached = self.jwks.read().ok().and_then(|jwks| {
+                jwks.get(uri)
+                    .filter(|(_, fetched_at)| fetched_at.elapsed() < self.ttl)
+                    .map(|(jwks, _)| jwks.clone())
+            });
+            if let Some(jwks) = cached {
+                return Ok(jwks);
+            }
+        }
+
+        let jwks: JwkSet = client
+            .get(uri)
+            .send()
+            .await
+            .map_err(|e| InternalError::io_err(&format!("Failed to fetch JWKS: {e}"), None))?
+            .json()
+            .await
+            .map_err(|e| {
+                InternalError::deserialize_error(&format!("Failed to parse JWKS: {e}"), None)
+            })?;
+        let jwks = Arc::new(jwks);
+
+        if let Ok(mut cached) = self.jwks.write() {
+            cached.insert(uri.to_string(), (jwks.clone(), Instant::now()));
+        }
+
+        Ok(jwks)
+    }
+
+    pub fn invalidate(&self, id: &Id) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.remove(id);
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.clear();
+        }
+    }
+
+    /// Invalidates definitions as they change in Mongo. Returns when change streams
+    /// are unsupported, leaving the time to live as the only invalidation, and
+    /// opens the stream again with backoff whenever it fails.
+    pub async fn watch(&self) {
+        let mut failures = 0;
+
+        loop {
+            let mut changes = match self
+                .store
+                .collection
+                .clone_with_type::<Document>()
+                .watch()
+                .await
+            {
+                Ok(changes) => {
+                    failures = 0;
+                    changes
+                }
+                Err(e) if change_streams_unsupported(&e) => {
+                    warn!("Definition cache relies on its time to live only: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = OPEN_BACKOFF.delay_in_seconds(failures);
+                    warn!(
+                        "Failed to open definition change stream, retrying in {} seconds: {}",
+                        delay, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+                    continue;
+                }
+            };
+
+            // Changes made while no stream was open are unknown.
+            self.invalidate_all();
+
+            while let Some(event) = changes.next().await {
+                let id = event.ok().and_then(|event| {
+                    event
+                        .document_key
+                        .and_then(|key| key.get_str("_id").ok().and_then(|id| id.parse().ok()))
+                });
+
+                match id {
+                    Some(id) => self.invalidate(&id),
+                    None => self.invalidate_all(),
+                }
+            }
+
+            warn!("Definition change stream closed, opening it again");
+            tokio::time::sleep(REOPEN_DELAY).await;
+        }
+    }
+
+    fn cached(&self, id: &Id) -> Option<CachedDefinition> {
+        let entries = self.entries.read().ok()?;
+        entries
+            .get(id)
+            .filter(|cached| cached.fetched_at.elapsed() < self.ttl)
+            .cloned()
+    }
+}
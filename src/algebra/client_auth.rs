@@ -0,0 +1,28 @@
+use crate::domain::ClientAuthMethod;
+use osentities::oauth_secret::OAuthSecret;
+use reqwest_middleware::RequestBuilder;
+use std::collections::BTreeMap;
+
+/// Authenticates `request` as the oauth client of `secret`, adding the credentials
+/// to `form` unless they go in the `Authorization` header.
+pub fn authenticate_client<'a>(
+    request: RequestBuilder,
+    form: &mut BTreeMap<&'a str, &'a str>,
+    auth_method: ClientAuthMethod,
+    secret: &'a OAuthSecret,
+) -> RequestBuilder {
+    match auth_method {
+        ClientAuthMethod::ClientSecretBasic => {
+            request.basic_auth(&secret.client_id, Some(&secret.client_secret))
+        }
+        ClientAuthMethod::ClientSecretPost => {
+            form.insert("client_id", &secret.client_id);
+            form.insert("client_secret", &secret.client_secret);
+            request
+        }
+        ClientAuthMethod::None => {
+            form.insert("client_id", &secret.client_id);
+            request
+        }
+    }
+}
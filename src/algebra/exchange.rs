@@ -0,0 +1,362 @@
+use crate::{
+    algebra::{connection_expiry, token_response, DefinitionCache, PkceStore, StorageExt},
+    domain::{ConnectionExpiry, Exchange, ExchangeTarget, NewConnection, RefreshError},
+    ParameterExt, SecretsClient,
+};
+use chrono::Utc;
+use osentities::{
+    algebra::MongoStore,
+    api_model_config::ContentType,
+    connection_definition::ConnectionDefinition,
+    connection_oauth_definition::{Computation, OAuthResponse},
+    id::prefix::IdPrefix,
+    oauth_secret::OAuthSecret,
+    record_metadata::RecordMetadata,
+    ApplicationError, Connection, Id, InternalError, OAuth, PicaError,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Exchanges an authorization code for tokens with the `init` request of the
+/// connection oauth definition, stores them in the secrets service and links them
+/// to the target connection, creating it when it is new.
+pub async fn exchange(
+    msg: Exchange,
+    secrets: Arc<SecretsClient>,
+    connections: Arc<MongoStore<Connection>>,
+    connection_definitions: Arc<MongoStore<ConnectionDefinition>>,
+    oauths: Arc<DefinitionCache>,
+    pkce: Arc<PkceStore>,
+    client: ClientWithMiddleware,
+) -> Result<Connection, RefreshError> {
+    let conn_oauth_id = &msg.connection_oauth_definition_id;
+    let cached_definition = oauths
+        .get(conn_oauth_id)
+        .await?
+        .ok_or(ApplicationError::not_found(
+            &format!("Connection oauth definition not found: {conn_oauth_id}"),
+            None,
+        ))?;
+
+    let connection = match &msg.target {
+        ExchangeTarget::Existing { connection_id } => {
+            let connection =
+                connections
+                    .get(*connection_id)
+                    .await?
+                    .ok_or(ApplicationError::not_found(
+                        &format!("Connection {connection_id} not found"),
+                        None,
+                    ))?;
+
+            match &connection.oauth {
+                Some(OAuth::Enabled {
+                    connection_oauth_definition_id,
+                    ..
+                }) if connection_oauth_definition_id == conn_oauth_id => connection,
+                _ => {
+                    return Err(ApplicationError::bad_request(
+                        &format!(
+                            "Connection {connection_id} does not use connection oauth definition {conn_oauth_id}"
+                        ),
+                        None,
+                    )
+                    .into())
+                }
+            }
+        }
+        ExchangeTarget::New { connection } => {
+            new_connection(
+                connection,
+                cached_definition.platform(),
+                &connection_definitions,
+            )
+            .await?
+        }
+    };
+
+    let code_verifier = match &msg.state {
+        Some(state) => Some(pkce.get(state).await?.ok_or(ApplicationError::not_found(
+            "No code verifier for this state, it expired or was already used",
+            None,
+        ))?),
+        None => None,
+    };
+
+    let payload = payload(&msg, code_verifier.as_deref());
+    let conn_oauth_definition = cached_definition.render(&payload)?;
+
+    let computation = conn_oauth_definition
+        .compute
+        .init
+        .computation
+        .clone()
+        .map(|computation| computation.compute::<Computation>(&payload))
+        .transpose()
+        .map_err(|e| {
+            warn!("Failed to compute oauth payload: {}", e);
+            InternalError::encryption_error("Failed to parse computation payload", None)
+        })?;
+
+    let body = with_code_verifier(
+        conn_oauth_definition.init_body(&payload)?,
+        code_verifier.as_deref(),
+    );
+    let query = conn_oauth_definition.init_query(computation.as_ref())?;
+    let headers = conn_oauth_definition.init_headers(computation.as_ref())?;
+
+    let request = client
+        .post(conn_oauth_definition.configuration.init.uri())
+        .headers(headers.unwrap_or_default());
+
+    let request = match conn_oauth_definition.configuration.init.content {
+        Some(ContentType::Json) => request.json(&body).query(&query),
+        Some(ContentType::Form) => request.form(&body).query(&query),
+        _ => request.query(&query),
+    }
+    .build()
+    .map_err(|e| {
+        warn!("Failed to build request: {}", e);
+        InternalError::io_err("Failed to build request", None)
+    })?;
+
+    let sent_at = Utc::now();
+    let response = client.execute(request).await.map_err(|e| {
+        warn!("Failed to execute request: {}", e);
+        InternalError::io_err("Failed to execute request", None)
+    })?;
+
+    let json = token_response(response, "authorization code exchange", &connection).await;
+
+    // The provider consumed the code unless the exchange failed before it could
+    // answer, in which case the verifier is kept so the exchange can be retried.
+    if let (Some(state), Ok(_) | Err(RefreshError::OAuth(_))) = (&msg.state, &json) {
+        if let Err(e) = pkce.remove(state).await {
+            warn!("Failed to remove code verifier: {}", e);
+        }
+    }
+
+    let json = json?;
+
+    let decoded: OAuthResponse = conn_oauth_definition
+        .compute
+        .init
+        .response
+        .compute(&json)
+        .map_err(|e| {
+            warn!("Failed to decode oauth response: {}", e);
+            InternalError::decryption_error("Failed to decode oauth response", None)
+        })?;
+
+    // The metadata is kept as the request payload so refreshes can render the
+    // same values into their templates.
+    let oauth_secret = OAuthSecret::from_init(
+        decoded,
+        msg.client_id.clone(),
+        msg.client_secret.clone(),
+        json.clone(),
+        Some(msg.metadata.clone()),
+    );
+
+    let ConnectionExpiry {
+        expires_in,
+        expires_at,
+        ..
+    } = connection_expiry(
+        &cached_definition,
+        &json,
+        &oauth_secret,
+        sent_at,
+        &oauths,
+        &client,
+        &connection,
+    )
+    .await;
+
+    let oauth = OAuth::Enabled {
+        connection_oauth_definition_id: *conn_oauth_id,
+        expires_at,
+        expires_in,
+    };
+
+    let secret_id = secrets
+        .create_secret(
+            connection.ownership.client_id.clone(),
+            oauth_secret.as_json(),
+            connection.environment,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Failed to create oauth secret: {}", e);
+            InternalError::io_err("Failed to create oauth secret", None)
+        })?
+        .id();
+
+    let stored = match msg.target {
+        ExchangeTarget::Existing { .. } => {
+            authorize(&connection, &oauth, &secret_id, &secrets, &connections).await
+        }
+        ExchangeTarget::New { .. } => {
+            let connection = Connection {
+                oauth: Some(oauth),
+                secrets_service_id: secret_id.clone(),
+                ..connection.clone()
+            };
+
+            connections
+                .create_one(&connection)
+                .await
+                .map(|_| connection)
+        }
+    };
+
+    match stored {
+        Ok(connection) => {
+            tracing::info!("Connection {} authorized", connection.id);
+            Ok(connection)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to store authorization of connection {}: {}",
+                connection.id, e
+            );
+            delete_secret(&secret_id, &connection, &secrets).await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Builds a connection from the caller's fields and the connection definition,
+/// which has to be an oauth definition of the same platform as `platform`.
+async fn new_connection(
+    new: &NewConnection,
+    platform: &str,
+    connection_definitions: &MongoStore<ConnectionDefinition>,
+) -> Result<Connection, PicaError> {
+    let id = new.connection_definition_id;
+    let definition = connection_definitions
+        .get_one_by_id(&id.to_string())
+        .await?
+        .ok_or(ApplicationError::not_found(
+            &format!("Connection definition not found: {id}"),
+            None,
+        ))?;
+
+    if !definition.is_oauth() || definition.platform != platform {
+        return Err(ApplicationError::bad_request(
+            &format!("Connection definition {id} is not an oauth definition of {platform}"),
+            None,
+        ));
+    }
+
+    Ok(Connection {
+        id: Id::now(IdPrefix::Connection),
+        platform_version: definition.platform_version.clone(),
+        connection_definition_id: definition.id,
+        r#type: definition.to_connection_type(),
+        key: format!(
+            "{}::{}::{}",
+            new.environment, definition.platform, new.group
+        )
+        .into(),
+        group: new.group.clone(),
+        name: new.name.clone(),
+        environment: new.environment,
+        platform: definition.platform.as_str().into(),
+        secrets_service_id: String::new(),
+        event_access_id: None,
+        access_key: None,
+        identity: new.identity.clone(),
+        identity_type: new.identity_type.clone(),
+        settings: definition.settings,
+        throughput: new.throughput.clone(),
+        ownership: new.ownership.clone(),
+        oauth: None,
+        has_error: false,
+        error: None,
+        record_metadata: RecordMetadata::default(),
+    })
+}
+
+/// Values the `init` templates are rendered against. The code, redirect URI and
+/// PKCE code verifier are merged into the caller's metadata.
+fn payload(msg: &Exchange, code_verifier: Option<&str>) -> Value {
+    let mut metadata = match &msg.metadata {
+        Value::Object(metadata) => metadata.clone(),
+        _ => Default::default(),
+    };
+    metadata.insert("code".to_string(), json!(msg.code));
+    metadata.insert("redirectUri".to_string(), json!(msg.redirect_uri));
+    if let Some(code_verifier) = code_verifier {
+        metadata.insert("codeVerifier".to_string(), json!(code_verifier));
+    }
+
+    json!({
+        "clientId": msg.client_id,
+        "clientSecret": msg.client_secret,
+        "metadata": metadata,
+    })
+}
+
+/// Adds the PKCE `code_verifier` to the rendered body unless the definition
+/// already sends it.
+fn with_code_verifier(body: Option<Value>, code_verifier: Option<&str>) -> Option<Value> {
+    let Some(code_verifier) = code_verifier else {
+        return body;
+    };
+
+    match body {
+        Some(Value::Object(mut body)) => {
+            body.entry("code_verifier")
+                .or_insert_with(|| json!(code_verifier));
+            Some(Value::Object(body))
+        }
+        None => Some(json!({ "code_verifier": code_verifier })),
+        body => body,
+    }
+}
+
+/// Replaces the credentials of an existing connection, deleting the secret it
+/// referenced before.
+async fn authorize(
+    connection: &Connection,
+    oauth: &OAuth,
+    secret_id: &str,
+    secrets: &SecretsClient,
+    connections: &MongoStore<Connection>,
+) -> Result<Connection, osentities::PicaError> {
+    let previous = connections
+        .authorize(&connection.id, oauth, secret_id)
+        .await?
+        .ok_or(ApplicationError::not_found(
+            &format!("Connection {} not found", connection.id),
+            None,
+        ))?;
+
+    if previous.secrets_service_id != secret_id {
+        delete_secret(&previous.secrets_service_id, &previous, secrets).await;
+    }
+
+    Ok(Connection {
+        oauth: Some(oauth.clone()),
+        secrets_service_id: secret_id.to_string(),
+        has_error: false,
+        error: None,
+        ..previous
+    })
+}
+
+async fn delete_secret(secret_id: &str, connection: &Connection, secrets: &SecretsClient) {
+    if let Err(e) = secrets
+        .delete_secret(
+            secret_id,
+            &connection.ownership.client_id,
+            &connection.environment,
+        )
+        .await
+    {
+        warn!("Failed to delete secret {}: {}", secret_id, e);
+    }
+}
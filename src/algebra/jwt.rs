@@ -0,0 +1,146 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, DecodingKey, Validation};
+use osentities::{InternalError, PicaError};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Time claims of a JWT.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JwtClaims {
+    pub exp: Option<i64>,
+    pub iat: Option<i64>,
+}
+
+impl JwtClaims {
+    fn from_claims(claims: &Value) -> Self {
+        let claim = |name: &str| {
+            claims
+                .get(name)
+                .and_then(|value| value.as_i64().or_else(|| value.as_f64().map(|v| v as i64)))
+        };
+
+        Self {
+            exp: claim("exp"),
+            iat: claim("iat"),
+        }
+    }
+
+    /// Expiry of the token. With both claims present the lifetime `exp - iat` is
+    /// counted from `received_at`, which keeps the clock of the issuer out of it.
+    pub fn expires_at(&self, received_at: DateTime<Utc>) -> Option<i64> {
+        match (self.exp, self.iat) {
+            (Some(exp), Some(iat)) if exp > iat => Some(received_at.timestamp() + exp - iat),
+            (exp, _) => exp,
+        }
+    }
+}
+
+/// Reads the claims of a JWT without verifying its signature. Returns `None` when
+/// the token is not a JWT.
+pub fn jwt_claims(token: &str) -> Option<JwtClaims> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Value = serde_json::from_slice(&payload).ok()?;
+
+    Some(JwtClaims::from_claims(&claims))
+}
+
+/// Reads the claims of a JWT after verifying its signature against `jwks`.
+/// Returns `None` when no key of `jwks` matches the `kid` of the token.
+pub fn verified_jwt_claims(token: &str, jwks: &JwkSet) -> Result<Option<JwtClaims>, PicaError> {
+    let header = decode_header(token).map_err(|e| {
+        InternalError::invalid_argument(&format!("Token is not a valid JWT: {e}"), None)
+    })?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    };
+    let Some(jwk) = jwk else {
+        return Ok(None);
+    };
+
+    let key = DecodingKey::from_jwk(jwk)
+        .map_err(|e| InternalError::invalid_argument(&format!("Unusable JWKS key: {e}"), None))?;
+
+    // Only the signature is checked here, expired tokens still carry their claims.
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims = HashSet::new();
+
+    let token = decode::<Value>(token, &key, &validation).map_err(|e| {
+        InternalError::invalid_argument(&format!("Failed to verify JWT: {e}"), None)
+    })?;
+
+    Ok(Some(JwtClaims::from_claims(&token.claims)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn token(claims: Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "HS256" }).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        format!("{header}.{payload}.signature")
+    }
+
+    fn received_at() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).expect("valid timestamp")
+    }
+
+    #[test]
+    fn claims_are_read_from_the_payload() {
+        assert_eq!(
+            jwt_claims(&token(
+                json!({ "exp": 1_600_003_600, "iat": 1_600_000_000.5 })
+            )),
+            Some(JwtClaims {
+                exp: Some(1_600_003_600),
+                iat: Some(1_600_000_000),
+            })
+        );
+        assert_eq!(
+            jwt_claims(&token(json!({ "exp": "soon" }))),
+            Some(JwtClaims::default())
+        );
+    }
+
+    #[test]
+    fn opaque_tokens_have_no_claims() {
+        assert_eq!(jwt_claims("opaque-access-token"), None);
+        assert_eq!(jwt_claims("header.not-base64!.signature"), None);
+    }
+
+    #[test]
+    fn lifetimes_count_from_when_the_token_was_received() {
+        let claims = JwtClaims {
+            exp: Some(1_600_003_600),
+            iat: Some(1_600_000_000),
+        };
+
+        assert_eq!(claims.expires_at(received_at()), Some(1_700_003_600));
+    }
+
+    #[test]
+    fn exp_is_used_as_is_without_a_usable_iat() {
+        let without_iat = JwtClaims {
+            exp: Some(1_600_003_600),
+            iat: None,
+        };
+        let issued_after_expiry = JwtClaims {
+            exp: Some(1_600_000_000),
+            iat: Some(1_600_003_600),
+        };
+
+        assert_eq!(without_iat.expires_at(received_at()), Some(1_600_003_600));
+        assert_eq!(
+            issued_after_expiry.expires_at(received_at()),
+            Some(1_600_000_000)
+        );
+        assert_eq!(JwtClaims::default().expires_at(received_at()), None);
+    }
+}
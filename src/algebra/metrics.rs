@@ -1,12 +1,28 @@
+use chrono::Utc;
 use metrics_exporter_prometheus::PrometheusBuilder;
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 pub const SUCCESSFULLY_REFRESHED_GAUGE: &str = "successfully_refreshed";
 pub const FAILED_TO_REFRESH_GAUGE: &str = "failed_to_refresh";
+pub const SUPERSEDED_GAUGE: &str = "superseded_refreshes";
 pub const REFRESH_TOTAL: &str = "refresh_total";
+pub const REFRESH_CYCLE_DURATION: &str = "refresh_cycle_duration_seconds";
 
 #[derive(Clone, Debug)]
 pub struct Metrics {
     is_installed: bool,
+    /// Last time the scheduler made progress, in seconds since the epoch. Only the
+    /// scheduler records it, so a wedged scheduler fails liveness even while other
+    /// loops keep completing cycles.
+    last_heartbeat_at: Arc<AtomicI64>,
+    /// Last time a reconciliation or refresh cycle completed, in seconds since the epoch.
+    last_cycle_at: Arc<AtomicI64>,
 }
 
 impl Metrics {
@@ -29,16 +45,52 @@ impl Metrics {
                 "The number of failed to refresh connections"
             );
 
+            metrics::describe_gauge!(
+                SUPERSEDED_GAUGE,
+                "The number of refreshes discarded because the connection was reauthorized"
+            );
+
             metrics::describe_gauge!(REFRESH_TOTAL, "The total number of refreshes");
 
-            Ok(Self { is_installed: true })
+            metrics::describe_histogram!(
+                REFRESH_CYCLE_DURATION,
+                metrics::Unit::Seconds,
+                "The time taken by a single refresh cycle"
+            );
+
+            Ok(Self::with_installed(true))
         } else {
-            Ok(Self {
-                is_installed: false,
-            })
+            Ok(Self::with_installed(false))
         }
     }
 
+    fn with_installed(is_installed: bool) -> Self {
+        Self {
+            is_installed,
+            last_heartbeat_at: Arc::new(AtomicI64::new(Utc::now().timestamp())),
+            last_cycle_at: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    pub fn record_heartbeat(&self) {
+        self.last_heartbeat_at
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn record_cycle_completed(&self) {
+        self.last_cycle_at
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn last_heartbeat_at(&self) -> i64 {
+        self.last_heartbeat_at.load(Ordering::Relaxed)
+    }
+
+    /// Zero until the first cycle completes.
+    pub fn last_cycle_at(&self) -> i64 {
+        self.last_cycle_at.load(Ordering::Relaxed)
+    }
+
     pub fn add_refreshed(&self, value: u64) {
         if self.is_installed {
             metrics::increment_gauge!(SUCCESSFULLY_REFRESHED_GAUGE, value as f64);
@@ -52,4 +104,17 @@ impl Metrics {
             metrics::increment_gauge!(REFRESH_TOTAL, value as f64);
         }
     }
+
+    pub fn add_superseded(&self, value: u64) {
+        if self.is_installed {
+            metrics::increment_gauge!(SUPERSEDED_GAUGE, value as f64);
+            metrics::increment_gauge!(REFRESH_TOTAL, value as f64);
+        }
+    }
+
+    pub fn record_cycle_duration(&self, duration: Duration) {
+        if self.is_installed {
+            metrics::histogram!(REFRESH_CYCLE_DURATION, duration.as_secs_f64());
+        }
+    }
 }
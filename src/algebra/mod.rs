@@ -1,11 +1,31 @@
+mod cache;
+mod client_auth;
+mod exchange;
+mod jwt;
 mod metrics;
+mod outbox;
 mod parameter;
+mod pkce;
 mod refresh;
+mod revoke;
+mod scheduler;
 mod secrets;
 mod storage;
+mod validate;
+mod watcher;
 
+pub use cache::*;
+pub use client_auth::*;
+pub use exchange::*;
+pub use jwt::*;
 pub use metrics::*;
+pub use outbox::*;
 pub use parameter::*;
+pub use pkce::*;
 pub use refresh::*;
+pub use revoke::*;
+pub use scheduler::*;
 pub use secrets::*;
 pub use storage::*;
+pub use validate::*;
+pub use watcher::*;
@@ -0,0 +1,346 @@
+use crate::{
+    algebra::{StorageExt, LAST_REFRESHED_AT_FIELD, REFRESH_FAILURE_FIELD},
+    domain::{Backoff, Lease, OutboxEntry, Refresh, RefreshError, Unit},
+    SecretsClient,
+};
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc},
+    Collection, Database,
+};
+use osentities::{
+    algebra::{CryptoExt, IOSCrypto, MongoStore},
+    error::PicaError as Error,
+    Connection, Id, InternalError, OAuth,
+};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+use uuid::Uuid;
+
+pub const OUTBOX_COLLECTION: &str = "connection-refresh-outbox";
+
+/// Encrypted store of refreshed credentials that have not yet been written to
+/// the secrets service and linked to their connection.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    collection: Collection<OutboxEntry>,
+    crypto: IOSCrypto,
+    backoff: Backoff,
+}
+
+impl Outbox {
+    pub fn new(db: &Database, crypto: IOSCrypto, backoff: Backoff) -> Self {
+        Self {
+            collection: db.collection(OUTBOX_COLLECTION),
+            crypto,
+            backoff,
+        }
+    }
+
+    /// Encrypts `secret` into a new entry that links it to `connection` with `oauth`.
+    pub async fn seal(
+        &self,
+        connection: &Connection,
+        secret: &Value,
+        oauth: OAuth,
+        clear_failure: bool,
+    ) -> Result<OutboxEntry, Error> {
+        let now = Utc::now().timestamp();
+        let secret = CryptoExt::encrypt(&self.crypto, secret.to_string()).await?;
+
+        Ok(OutboxEntry {
+            id: Uuid::new_v4().to_string(),
+            connection_id: connection.id,
+            buildable_id: connection.ownership.client_id.clone(),
+            environment: connection.environment,
+            secrets_service_id: connection.secrets_service_id.clone(),
+            secret,
+            oauth,
+            clear_failure,
+            created_secret_id: None,
+            attempts: 0,
+            created_at: now,
+            // Left to the refresh that staged it unless that refresh fails.
+            next_attempt_at: now + self.backoff.base_in_seconds(),
+            last_error: None,
+        })
+    }
+
+    pub async fn open(&self, entry: &OutboxEntry) -> Result<Value, Error> {
+        let secret = CryptoExt::decrypt(&self.crypto, entry.secret.clone(), None).await?;
+
+        serde_json::from_str(&secret).map_err(|e| {
+            warn!("Failed to deserialize outbox secret {}: {}", entry.id, e);
+            InternalError::deserialize_error("Failed to deserialize outbox secret", None)
+        })
+    }
+
+    pub async fn insert(&self, entry: &OutboxEntry) -> Result<Unit, Error> {
+        self.collection.insert_one(entry).await?;
+
+        Ok(())
+    }
+
+    /// Returns the oldest entry of `connection_id` that is still pending.
+    pub async fn pending(&self, connection_id: &Id) -> Result<Option<OutboxEntry>, Error> {
+        Ok(self
+            .collection
+            .find_one(doc! { "connectionId": connection_id.to_string() })
+            .sort(doc! { "createdAt": 1 })
+            .await?)
+    }
+
+    /// Returns at most `limit` entries whose next attempt is due at `now`.
+    pub async fn due(&self, now: i64, limit: i64) -> Result<Vec<OutboxEntry>, Error> {
+        Ok(self
+            .collection
+            .find(doc! { "nextAttemptAt": { "$lte": now } })
+            .sort(doc! { "nextAttemptAt": 1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    pub async fn created(&self, id: &str, secret_id: &str) -> Result<Unit, Error> {
+        self.collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "createdSecretId": secret_id } },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_failure(&self, entry: &OutboxEntry, error: &str) -> Result<Unit, Error> {
+        let attempts = entry.attempts + 1;
+        let next_attempt_at = Utc::now().timestamp() + self.backoff.delay_in_seconds(attempts);
+
+        self.collection
+            .update_one(
+                doc! { "_id": &entry.id },
+                doc! {
+                    "$set": {
+                        "attempts": attempts,
+                        "nextAttemptAt": next_attempt_at,
+                        "lastError": error,
+                    }
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<Unit, Error> {
+        self.collection.delete_one(doc! { "_id": id }).await?;
+
+        Ok(())
+    }
+}
+
+/// Writes the secret of `entry` to the secrets service and links it to the
+/// connection, removing the entry once both have succeeded. Returns the id of the
+/// new secret. Failures are recorded on the entry so it is retried later.
+pub async fn complete(
+    entry: &OutboxEntry,
+    secrets: &SecretsClient,
+    connections: &MongoStore<Connection>,
+) -> Result<String, RefreshError> {
+    let outbox = secrets.outbox();
+
+    let result = async {
+        let secret_id = match &entry.created_secret_id {
+            Some(secret_id) => secret_id.clone(),
+            None => {
+                let secret = outbox.open(entry).await?;
+                let secret_id = secrets
+                    .create_secret(entry.buildable_id.clone(), secret, entry.environment)
+                    .await
+                    .map_err(|e| {
+                        warn!("Failed to create oauth secret: {}", e);
+                        InternalError::io_err("Failed to create oauth secret", None)
+                    })?
+                    .id();
+
+                // An unrecorded secret would be created again by the next attempt,
+                // so it is deleted before the failure is reported.
+                if let Err(e) = outbox.created(&entry.id, &secret_id).await {
+                    warn!(
+                        "Failed to record secret {} on outbox entry {}: {}",
+                        secret_id, entry.id, e
+                    );
+                    if let Err(e) = secrets
+                        .delete_secret(&secret_id, &entry.buildable_id, &entry.environment)
+                        .await
+                    {
+                        warn!("Failed to delete unrecorded secret {}: {}", secret_id, e);
+                    }
+                    return Err(e);
+                }
+
+                secret_id
+            }
+        };
+
+        let mut data = doc! {
+            "$set": {
+                "oauth": bson::to_bson(&entry.oauth).map_err(|e| {
+                    warn!("Failed to serialize oauth: {}", e);
+                    InternalError::serialize_error("Failed to serialize oauth", None)
+                })?,
+                "secretsServiceId": &secret_id,
+                LAST_REFRESHED_AT_FIELD: Utc::now().timestamp(),
+            },
+        };
+        if entry.clear_failure {
+            data.insert("$unset", doc! { REFRESH_FAILURE_FIELD: "" });
+        }
+
+        // The update only applies while the connection still points at the secret
+        // the refresh started from, so a reauthorization is never overwritten. An
+        // entry left behind after its update was applied finds the connection on
+        // its own secret already and is simply completed.
+        let updated = connections
+            .update_if_unchanged(
+                &entry.connection_id,
+                &entry.secrets_service_id,
+                &secret_id,
+                data,
+            )
+            .await
+            .map_err(|e| {
+                warn!("Failed to update connection: {}", e);
+                InternalError::io_err("Failed to update connection", None)
+            })?;
+
+        Ok::<_, Error>((secret_id, updated))
+    }
+    .await;
+
+    let (secret_id, updated) = match result {
+        Ok(completed) => completed,
+        Err(e) => {
+            let error = e.to_string();
+            if let Err(e) = outbox.record_failure(entry, &error).await {
+                warn!(
+                    "Failed to record failure on outbox entry {}: {}",
+                    entry.id, e
+                );
+            }
+            return Err(e.into());
+        }
+    };
+
+    if !updated {
+        warn!(
+            "Connection {} was reauthorized during refresh, discarding secret {}",
+            entry.connection_id, secret_id
+        );
+        if let Err(e) = secrets
+            .delete_secret(&secret_id, &entry.buildable_id, &entry.environment)
+            .await
+        {
+            warn!("Failed to delete orphaned secret {}: {}", secret_id, e);
+        }
+    }
+
+    if let Err(e) = outbox.remove(&entry.id).await {
+        warn!("Failed to remove outbox entry {}: {}", entry.id, e);
+    }
+
+    if updated {
+        Ok(secret_id)
+    } else {
+        Err(RefreshError::Superseded)
+    }
+}
+
+/// Completes outbox entries left behind by failed or interrupted refreshes every
+/// `interval` until shutdown.
+pub async fn drain_outbox(
+    msg: Refresh,
+    secrets: Arc<SecretsClient>,
+    connections: Arc<MongoStore<Connection>>,
+    interval: Duration,
+    limit: i64,
+) -> Result<Unit, Error> {
+    if msg.dry_run() {
+        tracing::info!("Outbox drain disabled during dry run");
+        return Ok(());
+    }
+
+    loop {
+        match secrets.outbox().due(Utc::now().timestamp(), limit).await {
+            Ok(entries) => {
+                for entry in entries {
+                    if msg.shutdown().is_triggered() {
+                        break;
+                    }
+                    drain(&entry, &msg.lease().attempt(), &secrets, &connections).await;
+                }
+            }
+            Err(e) => warn!("Failed to read outbox entries: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = msg.shutdown().triggered() => {
+                tracing::info!("Outbox drain stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn drain(
+    entry: &OutboxEntry,
+    lease: &Lease,
+    secrets: &SecretsClient,
+    connections: &MongoStore<Connection>,
+) {
+    let id = entry.connection_id;
+
+    // A connection that no longer exists cannot be claimed, but its entry is still
+    // completed so the secret is cleaned up and the entry removed.
+    let claimed = match connections.claim(&id, lease).await {
+        Ok(true) => true,
+        Ok(false) => match connections.get(id).await {
+            Ok(None) => false,
+            Ok(Some(_)) => {
+                tracing::debug!("Connection {} is leased, retrying outbox later", id);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read connection {}: {}", id, e);
+                return;
+            }
+        },
+        Err(e) => {
+            warn!("Failed to claim connection {}: {}", id, e);
+            return;
+        }
+    };
+
+    match complete(entry, secrets, connections).await {
+        Ok(secret_id) => tracing::info!(
+            "Completed outbox entry {} of connection {} with secret {}",
+            entry.id,
+            id,
+            secret_id
+        ),
+        Err(RefreshError::Superseded) => {
+            tracing::info!("Discarded outbox entry {} of connection {}", entry.id, id)
+        }
+        Err(e) => warn!("Failed to complete outbox entry {}: {}", entry.id, e),
+    }
+
+    if claimed {
+        if let Err(e) = connections.release(&id, lease).await {
+            warn!("Failed to release lease on connection {}: {}", id, e);
+        }
+    }
+}
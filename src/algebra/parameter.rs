@@ -1,5 +1,6 @@
 use handlebars::Handlebars;
 use osentities::{
+    api_model_config::ApiModelConfig,
     connection_oauth_definition::{Computation, ComputeRequest, ConnectionOAuthDefinition},
     error::PicaError as Error,
     oauth_secret::OAuthSecret,
@@ -17,31 +18,50 @@ pub trait ParameterExt {
     fn headers(&self, computation: Option<&Computation>) -> Result<Option<HeaderMap>, Error>;
     fn body(&self, secret: &OAuthSecret) -> Result<Option<Value>, Error>;
     fn query(&self, computation: Option<&Computation>) -> Result<Option<Value>, Error>;
+    /// Headers of the authorization code exchange.
+    fn init_headers(&self, computation: Option<&Computation>) -> Result<Option<HeaderMap>, Error>;
+    /// Body of the authorization code exchange, rendered against `payload`.
+    fn init_body(&self, payload: &Value) -> Result<Option<Value>, Error>;
+    /// Query parameters of the authorization code exchange.
+    fn init_query(&self, computation: Option<&Computation>) -> Result<Option<Value>, Error>;
 }
 
 impl ParameterExt for ConnectionOAuthDefinition {
     fn headers(&self, computation: Option<&Computation>) -> Result<Option<HeaderMap>, Error> {
-        headers(self, computation)
+        headers(&self.configuration.refresh, computation)
     }
 
     fn body(&self, secret: &OAuthSecret) -> Result<Option<Value>, Error> {
-        body(secret, &self.compute.refresh)
+        let payload = serde_json::to_value(secret).map_err(|e| {
+            warn!("Failed to serialize secret: {}", e);
+            InternalError::encryption_error("Failed to serialize secret", None)
+        })?;
+
+        body(&payload, &self.compute.refresh)
     }
 
     fn query(&self, computation: Option<&Computation>) -> Result<Option<Value>, Error> {
-        query(self, computation)
+        query(&self.configuration.refresh, computation)
+    }
+
+    fn init_headers(&self, computation: Option<&Computation>) -> Result<Option<HeaderMap>, Error> {
+        headers(&self.configuration.init, computation)
+    }
+
+    fn init_body(&self, payload: &Value) -> Result<Option<Value>, Error> {
+        body(payload, &self.compute.init)
+    }
+
+    fn init_query(&self, computation: Option<&Computation>) -> Result<Option<Value>, Error> {
+        query(&self.configuration.init, computation)
     }
 }
 
-fn body(secret: &OAuthSecret, refresh: &ComputeRequest) -> Result<Option<Value>, Error> {
-    let payload = serde_json::to_value(secret).map_err(|e| {
-        warn!("Failed to serialize secret: {}", e);
-        InternalError::encryption_error("Failed to serialize secret", None)
-    })?;
-    let computation = refresh
+fn body(payload: &Value, request: &ComputeRequest) -> Result<Option<Value>, Error> {
+    let computation = request
         .computation
         .clone()
-        .map(|computation| computation.compute::<Computation>(&payload))
+        .map(|computation| computation.compute::<Computation>(payload))
         .transpose()
         .map_err(|e| {
             warn!("Failed to compute oauth payload: {}", e);
@@ -59,7 +79,7 @@ fn body(secret: &OAuthSecret, refresh: &ComputeRequest) -> Result<Option<Value>,
             })?;
 
             let body = handlebars
-                .render_template(&body_str, &payload)
+                .render_template(&body_str, payload)
                 .map_err(|e| {
                     warn!("Failed to render body: {}", e);
                     InternalError::encryption_error("Failed to render body template", None)
@@ -78,24 +98,19 @@ fn body(secret: &OAuthSecret, refresh: &ComputeRequest) -> Result<Option<Value>,
 }
 
 fn query(
-    definition: &ConnectionOAuthDefinition,
+    config: &ApiModelConfig,
     computation: Option<&Computation>,
 ) -> Result<Option<Value>, Error> {
-    let query_params = definition
-        .configuration
-        .refresh
-        .query_params
-        .as_ref()
-        .map(|query_params| {
-            let mut map = HashMap::new();
-            for (key, value) in query_params {
-                let key = key.to_string();
-                let value = value.as_str();
-
-                map.insert(key, value.to_string());
-            }
-            map
-        });
+    let query_params = config.query_params.as_ref().map(|query_params| {
+        let mut map = HashMap::new();
+        for (key, value) in query_params {
+            let key = key.to_string();
+            let value = value.as_str();
+
+            map.insert(key, value.to_string());
+        }
+        map
+    });
 
     match query_params {
         Some(query_params) => {
@@ -133,24 +148,19 @@ fn query(
 }
 
 fn headers(
-    definition: &ConnectionOAuthDefinition,
+    config: &ApiModelConfig,
     computation: Option<&Computation>,
 ) -> Result<Option<HeaderMap>, Error> {
-    let headers = definition
-        .configuration
-        .refresh
-        .headers
-        .as_ref()
-        .and_then(|headers| {
-            let mut map = HashMap::new();
-            for (key, value) in headers {
-                let key = key.to_string();
-                let value = value.to_str().ok()?;
-
-                map.insert(key, value.to_string());
-            }
-            Some(map)
-        });
+    let headers = config.headers.as_ref().and_then(|headers| {
+        let mut map = HashMap::new();
+        for (key, value) in headers {
+            let key = key.to_string();
+            let value = value.to_str().ok()?;
+
+            map.insert(key, value.to_string());
+        }
+        Some(map)
+    });
 
     match headers {
         Some(headers) => {
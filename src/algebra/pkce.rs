@@ -0,0 +1,89 @@
+use crate::domain::{Pkce, PkceChallenge, PkceMethod, Unit};
+use chrono::Utc;
+use mongodb::{bson::doc, Collection, Database};
+use osentities::error::PicaError as Error;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const PKCE_COLLECTION: &str = "connection-oauth-pkce";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PkceEntry {
+    /// OAuth state of the authorization request.
+    #[serde(rename = "_id")]
+    state: String,
+    code_verifier: String,
+    expires_at: i64,
+}
+
+/// Code verifiers of authorization requests in flight, kept until their code is
+/// exchanged or `ttl_in_seconds` has passed.
+#[derive(Debug, Clone)]
+pub struct PkceStore {
+    collection: Collection<PkceEntry>,
+    ttl_in_seconds: i64,
+}
+
+impl PkceStore {
+    pub fn new(db: &Database, ttl_in_seconds: i64) -> Self {
+        Self {
+            collection: db.collection(PKCE_COLLECTION),
+            ttl_in_seconds,
+        }
+    }
+
+    /// Generates a verifier for a new authorization request and stores it under
+    /// `state`, or under a random state when none is given.
+    pub async fn start(
+        &self,
+        state: Option<String>,
+        method: PkceMethod,
+    ) -> Result<PkceChallenge, Error> {
+        let now = Utc::now().timestamp();
+        self.purge(now).await?;
+
+        let pkce = Pkce::generate(method);
+        let entry = PkceEntry {
+            state: state.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            code_verifier: pkce.code_verifier,
+            expires_at: now + self.ttl_in_seconds,
+        };
+        self.collection.insert_one(&entry).await?;
+
+        Ok(PkceChallenge {
+            state: entry.state,
+            code_challenge: pkce.code_challenge,
+            code_challenge_method: pkce.code_challenge_method,
+            expires_at: entry.expires_at,
+        })
+    }
+
+    /// Returns the verifier stored under `state`, unless it expired.
+    pub async fn get(&self, state: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .collection
+            .find_one(doc! {
+                "_id": state,
+                "expiresAt": { "$gt": Utc::now().timestamp() },
+            })
+            .await?
+            .map(|entry| entry.code_verifier))
+    }
+
+    /// Removes the verifier stored under `state` once the provider consumed the
+    /// code it belongs to.
+    pub async fn remove(&self, state: &str) -> Result<Unit, Error> {
+        self.collection.delete_one(doc! { "_id": state }).await?;
+
+        Ok(())
+    }
+
+    async fn purge(&self, now: i64) -> Result<Unit, Error> {
+        self.collection
+            .delete_many(doc! { "expiresAt": { "$lte": now } })
+            .await?;
+
+        Ok(())
+    }
+}
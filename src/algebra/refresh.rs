@@ -1,23 +1,34 @@
 use crate::{
-    algebra::StorageExt,
-    domain::{Refresh, Trigger, Unit},
+    algebra::{
+        authenticate_client, complete, jwt_claims, validate, verified_jwt_claims, CachedDefinition,
+        DefinitionCache, DefinitionStorageExt, StorageExt,
+    },
+    domain::{
+        normalize_expires_at, ConnectionExpiry, ExpiryPolicy, Grant, JwtSource, Lease, OAuthError,
+        OutboxEntry, Refresh, RefreshError, RefreshSummary, RenderedRequest, Trigger, Unit,
+    },
     Metrics, ParameterExt, Refreshed, SecretsClient,
 };
-use chrono::{Duration, Utc};
-use mongodb::bson::{self, doc};
+use chrono::{DateTime, Duration, Utc};
+use futures::{stream, StreamExt};
 use osentities::{
     algebra::MongoStore,
     api_model_config::ContentType,
-    connection_oauth_definition::{Computation, ConnectionOAuthDefinition, OAuthResponse},
+    connection_oauth_definition::{Computation, OAuthResponse},
     error::PicaError as Error,
     oauth_secret::OAuthSecret,
-    ApplicationError, Connection, DefaultTemplate, InternalError, OAuth, TemplateExt,
+    ApplicationError, Connection, Id, InternalError, OAuth,
 };
 use reqwest_middleware::ClientWithMiddleware;
 use serde_json::json;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
 use tracing::warn;
 
+/// Maximum number of connections without an `expires_at` picked up per cycle.
+pub const WITHOUT_EXPIRY_LIMIT: u64 = 100;
+/// Attempts at staging refreshed credentials in the outbox.
+const STAGE_ATTEMPTS: u32 = 3;
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct OAuthJson {
     #[serde(flatten)]
@@ -35,21 +46,11 @@ pub async fn refresh(
     msg: Refresh,
     connections_store: Arc<MongoStore<Connection>>,
     secrets: Arc<SecretsClient>,
-    oauths: Arc<MongoStore<ConnectionOAuthDefinition>>,
+    oauths: Arc<DefinitionCache>,
     client: ClientWithMiddleware,
     metrics: Arc<Metrics>,
-) -> Result<Unit, Error> {
-    let refresh_before = Utc::now();
-    let refresh_after = refresh_before + Duration::minutes(msg.refresh_before_in_minutes());
-    tracing::info!(
-        "Searching for connections to refresh between {} and {}",
-        refresh_before.timestamp(),
-        refresh_after.timestamp()
-    );
-
-    tracing::info!("Searching for connections to refresh");
-    let connections = connections_store
-        .get_by(&refresh_before, &refresh_after)
+) -> Result<RefreshSummary, Error> {
+    let connections = find_connections(&msg, &connections_store, &oauths)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get connections to refresh: {:?}", e);
@@ -58,52 +59,305 @@ pub async fn refresh(
 
     tracing::info!("Found {} connections to refresh", connections.len());
 
-    let mut requests = vec![];
-    for connection in &connections {
-        let trigger_message = Trigger::new(connection.clone());
-        let result = trigger(
-            trigger_message,
-            secrets.clone(),
-            connections_store.clone(),
-            oauths.clone(),
-            client.clone(),
-        );
+    refresh_connections(
+        &msg,
+        connections,
+        connections_store,
+        secrets,
+        oauths,
+        client,
+        metrics,
+    )
+    .await
+}
+
+/// Refreshes `connections`, each holding one of the permits of `msg` while it
+/// runs so concurrent callers share a single limit. Each connection is leased and
+/// read again before the refresh, and skipped when another replica has refreshed
+/// it since it was listed.
+pub async fn refresh_connections(
+    msg: &Refresh,
+    connections: Vec<Connection>,
+    connections_store: Arc<MongoStore<Connection>>,
+    secrets: Arc<SecretsClient>,
+    oauths: Arc<DefinitionCache>,
+    client: ClientWithMiddleware,
+    metrics: Arc<Metrics>,
+) -> Result<RefreshSummary, Error> {
+    let started = Instant::now();
+    // Once shutdown fires no further connections are started, while the ones in
+    // flight are driven to completion so rotated tokens are always persisted.
+    let shutdown = msg.shutdown().clone();
+    let pending =
+        stream::iter(connections).take_until(Box::pin(async move { shutdown.triggered().await }));
+
+    let mut results = pending
+        .map(|connection| {
+            let lease = msg.lease().attempt();
+            let backoff = msg.backoff();
+            let dry_run = msg.dry_run();
+            let permits = msg.permits().clone();
+            let shutdown = msg.shutdown().clone();
+            let secrets = secrets.clone();
+            let connections_store = connections_store.clone();
+            let oauths = oauths.clone();
+            let client = client.clone();
+
+            async move {
+                if dry_run {
+                    return trigger(
+                        Trigger::new(connection).with_dry_run(true),
+                        secrets,
+                        connections_store,
+                        oauths,
+                        client,
+                    )
+                    .await
+                    .map(Some);
+                }
+
+                let Ok(_permit) = permits.acquire_owned().await else {
+                    return Ok(None);
+                };
+                // Shutdown may have fired while waiting for a permit.
+                if shutdown.is_triggered() {
+                    return Ok(None);
+                }
+
+                let id = connection.id;
+                let claimed = connections_store.claim(&id, &lease).await?;
+                if !claimed {
+                    tracing::debug!("Connection {} is deleted or leased by another task", id);
+                    return Ok(None);
+                }
+
+                // The refresh runs on the connection as read under the lease, never on
+                // the listed snapshot, whose refresh token may have been rotated since.
+                let current = match connections_store.get(id).await {
+                    Ok(Some(current)) if unchanged(&connection, &current) => current,
+                    Ok(_) => {
+                        tracing::debug!("Connection {} changed since it was listed", id);
+                        release(&connections_store, &id, &lease).await;
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        release(&connections_store, &id, &lease).await;
+                        return Err(e.into());
+                    }
+                };
+
+                // Credentials a previous attempt received but could not persist are
+                // completed first, since the provider may have revoked the old ones.
+                let result = match secrets.outbox().pending(&id).await {
+                    Ok(Some(entry)) => {
+                        tracing::info!("Completing pending refresh of connection {}", id);
+                        complete(&entry, &secrets, &connections_store)
+                            .await
+                            .map(|_| refreshed(&current))
+                    }
+                    Ok(None) => {
+                        trigger(
+                            Trigger::new(current),
+                            secrets,
+                            connections_store.clone(),
+                            oauths,
+                            client,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+
+                if let Err(RefreshError::Superseded) = &result {
+                    tracing::info!("Connection {} was reauthorized during refresh", id);
+                } else if let Err(e) = &result {
+                    let error = e.to_string();
+                    let recorded = if e.is_terminal() {
+                        warn!("Connection {} requires reauthorization: {}", id, error);
+                        connections_store
+                            .require_reauthorization(&id, &connection.secrets_service_id, &error)
+                            .await
+                    } else {
+                        if e.is_configuration_error() {
+                            tracing::error!(
+                                "Refresh of connection {} failed due to provider configuration: {}",
+                                id,
+                                error
+                            );
+                        }
+                        connections_store
+                            .record_failure(&id, &error, &backoff)
+                            .await
+                    };
+
+                    if let Err(e) = recorded {
+                        warn!(
+                            "Failed to record refresh failure on connection {}: {}",
+                            id, e
+                        );
+                    }
+                }
+
+                release(&connections_store, &id, &lease).await;
+
+                result.map(Some)
+            }
+        })
+        .buffer_unordered(msg.concurrency().max(1));
 
-        requests.push(result);
+    let mut summary = RefreshSummary::default();
+    while let Some(result) = results.next().await {
+        if msg.heartbeat() {
+            metrics.record_heartbeat();
+        }
+        match result {
+            Ok(Some(refreshed)) if msg.dry_run() => summary.rendered.push(refreshed),
+            Ok(Some(refreshed)) => {
+                summary.refreshed += 1;
+                tracing::debug!("Refreshed connection: {:?}", refreshed);
+            }
+            Ok(None) => summary.skipped += 1,
+            Err(RefreshError::Superseded) => summary.superseded += 1,
+            Err(e) => {
+                summary.failed += 1;
+                tracing::warn!("Failed to refresh connection: {:?}", e);
+            }
+        }
     }
 
-    let results = futures::future::join_all(requests).await;
+    let elapsed = started.elapsed();
+    tracing::info!(
+        "Refresh cycle finished in {} ms: {} refreshed, {} failed, {} skipped, {} superseded",
+        elapsed.as_millis(),
+        summary.refreshed,
+        summary.failed,
+        summary.skipped,
+        summary.superseded
+    );
+
+    if msg.dry_run() {
+        return Ok(summary);
+    }
 
-    let (successes, failures): (Vec<_>, Vec<_>) =
-        results.into_iter().partition(|result| result.is_ok());
+    metrics.add_refreshed(summary.refreshed);
+    metrics.add_failed_to_refresh(summary.failed);
+    metrics.add_superseded(summary.superseded);
+    metrics.record_cycle_duration(elapsed);
 
-    if !successes.is_empty() {
-        tracing::info!("Refreshed {} connections: {:?}", successes.len(), successes);
+    Ok(summary)
+}
+
+async fn release(connections_store: &MongoStore<Connection>, id: &Id, lease: &Lease) {
+    if let Err(e) = connections_store.release(id, lease).await {
+        warn!("Failed to release lease on connection {}: {}", id, e);
     }
+}
 
-    if !failures.is_empty() {
-        tracing::info!(
-            "Failed to refresh {} connections: {:?}",
-            failures.len(),
-            failures
-        );
+/// Inserts `entry` into the outbox, retrying a few times since the secrets service
+/// is only called once the rotated credentials are safely staged.
+async fn stage(
+    entry: &OutboxEntry,
+    secrets: &SecretsClient,
+    connection: &Connection,
+) -> Result<Unit, RefreshError> {
+    let mut attempt = 1;
+    loop {
+        match secrets.outbox().insert(entry).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < STAGE_ATTEMPTS => {
+                warn!(
+                    "Failed to stage refreshed secret of connection {} in the outbox, retrying: {}",
+                    connection.id, e
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(100 << attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to stage refreshed secret of connection {} in the outbox: {}",
+                    connection.id,
+                    e
+                );
+                return Err(e.into());
+            }
+        }
     }
+}
 
-    metrics.add_refreshed(successes.len() as u64);
-    metrics.add_failed_to_refresh(failures.len() as u64);
+/// Every refresh stores a new secret, so a connection refreshed elsewhere since it
+/// was listed differs in its secret even when its expiry stayed the same.
+fn unchanged(listed: &Connection, current: &Connection) -> bool {
+    expires_at(listed) == expires_at(current)
+        && listed.secrets_service_id == current.secrets_service_id
+}
 
-    Ok(())
+fn refreshed(connection: &Connection) -> Refreshed {
+    Refreshed::new(
+        connection.id.to_string().as_str(),
+        json!({ "id": connection.id.to_string() }),
+    )
+}
+
+pub fn expires_at(connection: &Connection) -> Option<i64> {
+    match &connection.oauth {
+        Some(OAuth::Enabled { expires_at, .. }) => *expires_at,
+        _ => None,
+    }
+}
+
+/// Connections the refresh would pick up right now.
+pub async fn find_connections(
+    msg: &Refresh,
+    connections_store: &MongoStore<Connection>,
+    oauths: &DefinitionCache,
+) -> Result<Vec<Connection>, Error> {
+    match msg.catch_up() {
+        Some(catch_up) => {
+            let expired_before = Utc::now();
+            let expired_after = expired_before - Duration::minutes(catch_up.max_age_in_minutes());
+            tracing::info!(
+                "Searching for up to {} connections that expired between {} and {}",
+                catch_up.limit(),
+                expired_after.timestamp(),
+                expired_before.timestamp()
+            );
+
+            connections_store
+                .get_expired(&expired_after, &expired_before, catch_up.limit())
+                .await
+        }
+        None => {
+            let refresh_before = Utc::now();
+            let refresh_after = refresh_before + Duration::minutes(msg.refresh_before_in_minutes());
+            tracing::info!(
+                "Searching for connections to refresh between {} and {}",
+                refresh_before.timestamp(),
+                refresh_after.timestamp()
+            );
+
+            let mut connections = connections_store
+                .get_by(&refresh_before, &refresh_after)
+                .await?;
+
+            let definition_ids = oauths.store().get_with_expiry_policy().await?;
+            connections.extend(
+                connections_store
+                    .get_without_expiry(&definition_ids, WITHOUT_EXPIRY_LIMIT)
+                    .await?,
+            );
+
+            Ok(connections)
+        }
+    }
 }
 
 pub async fn trigger(
     msg: Trigger,
     secrets: Arc<SecretsClient>,
     connections: Arc<MongoStore<Connection>>,
-    oauths: Arc<MongoStore<ConnectionOAuthDefinition>>,
+    oauths: Arc<DefinitionCache>,
     client: ClientWithMiddleware,
-) -> Result<Refreshed, Error> {
-    let template = DefaultTemplate::default();
-
+) -> Result<Refreshed, RefreshError> {
     let conn_oauth_id = match &msg.connection().oauth {
         Some(OAuth::Enabled {
             connection_oauth_definition_id: conn_oauth_definition_id,
@@ -115,10 +369,8 @@ pub async fn trigger(
         )),
     }?;
 
-    let conn_oauth_definition = oauths
-        .get_one(doc! {
-            "_id": conn_oauth_id.to_string(),
-        })
+    let cached_definition = oauths
+        .get(conn_oauth_id)
         .await
         .map_err(|e| {
             warn!("Failed to get connection oauth definition: {}", e);
@@ -145,11 +397,7 @@ pub async fn trigger(
         InternalError::serialize_error("Failed to serialize secret", None)
     })?;
 
-    let conn_oauth_definition = if conn_oauth_definition.is_full_template_enabled {
-        template.render_as(&conn_oauth_definition, Some(&compute_payload))?
-    } else {
-        conn_oauth_definition
-    };
+    let conn_oauth_definition = cached_definition.render(&compute_payload)?;
 
     let computation = conn_oauth_definition
         .compute
@@ -163,7 +411,6 @@ pub async fn trigger(
             InternalError::encryption_error("Failed to parse computation payload", None)
         })?;
 
-    let body = conn_oauth_definition.body(&secret)?;
     let query = conn_oauth_definition.query(computation.as_ref())?;
     let headers = conn_oauth_definition.headers(computation.as_ref())?;
 
@@ -171,10 +418,37 @@ pub async fn trigger(
         .post(conn_oauth_definition.configuration.refresh.uri())
         .headers(headers.unwrap_or_default());
 
-    let request = match conn_oauth_definition.configuration.refresh.content {
-        Some(ContentType::Json) => request.json(&body).query(&query),
-        Some(ContentType::Form) => request.form(&body).query(&query),
-        _ => request.query(&query),
+    let request = match cached_definition.grant() {
+        Grant::RefreshToken => {
+            let body = conn_oauth_definition.body(&secret)?;
+
+            match conn_oauth_definition.configuration.refresh.content {
+                Some(ContentType::Json) => request.json(&body).query(&query),
+                Some(ContentType::Form) => request.form(&body).query(&query),
+                _ => request.query(&query),
+            }
+        }
+        Grant::ClientCredentials {
+            scope,
+            audience,
+            auth_method,
+        } => {
+            let mut form = BTreeMap::from([("grant_type", "client_credentials")]);
+            if let Some(scope) = scope {
+                form.insert("scope", scope);
+            }
+            if let Some(audience) = audience {
+                form.insert("audience", audience);
+            }
+
+            let request = authenticate_client(request, &mut form, *auth_method, &secret);
+
+            // RFC 6749 sends the grant as a form, but some providers expect JSON.
+            match conn_oauth_definition.configuration.refresh.content {
+                Some(ContentType::Json) => request.json(&form).query(&query),
+                _ => request.form(&form).query(&query),
+            }
+        }
     }
     .build()
     .map_err(|e| {
@@ -182,15 +456,44 @@ pub async fn trigger(
         InternalError::io_err("Failed to build request", None)
     })?;
 
+    if msg.dry_run() {
+        let rendered = RenderedRequest::new(
+            request.method().as_str(),
+            request.url().as_str(),
+            request
+                .headers()
+                .iter()
+                .filter_map(|(key, value)| Some((key.as_str(), value.to_str().ok()?))),
+            request.body().and_then(|body| body.as_bytes()),
+            &[
+                secret.access_token.as_str(),
+                secret.client_secret.as_str(),
+                secret.refresh_token.as_deref().unwrap_or_default(),
+            ],
+        );
+        tracing::info!(
+            "Dry run of connection {}:\n{}",
+            msg.connection().id,
+            rendered
+        );
+
+        return Ok(Refreshed::new(
+            msg.connection().id.to_string().as_str(),
+            serde_json::to_value(&rendered).map_err(|e| {
+                InternalError::serialize_error(&format!("Failed to serialize request: {e}"), None)
+            })?,
+        ));
+    }
+
+    // Expiries are counted from when the request was sent rather than from when the
+    // response was handled, so a token never looks valid for longer than it is.
+    let sent_at = Utc::now();
     let response = client.execute(request).await.map_err(|e| {
         warn!("Failed to execute request: {}", e);
         InternalError::io_err("Failed to execute request", None)
     })?;
 
-    let json = response.json::<serde_json::Value>().await.map_err(|e| {
-        warn!("Failed to parse response: {}", e);
-        InternalError::decryption_error("Failed to parse response", None)
-    })?;
+    let json = token_response(response, "refresh", msg.connection()).await?;
 
     // This is done because some platforms do not return a refresh token in the response
     // (i.e. Salesforce). In these cases, we hold on to the original refresh token as a backup.
@@ -210,49 +513,248 @@ pub async fn trigger(
             InternalError::decryption_error("Failed to decode oauth response", None)
         })?;
 
-    let oauth_secret = secret.from_refresh(decoded, None, None, json);
-    let secret = secrets
-        .create_secret(
-            msg.connection().clone().ownership.client_id,
-            oauth_secret.as_json(),
-            msg.connection().environment,
-        )
-        .await
-        .map_err(|e| {
-            warn!("Failed to create oauth secret: {}", e);
-            InternalError::io_err("Failed to create oauth secret", None)
-        })?;
+    let oauth_secret = match cached_definition.grant() {
+        Grant::RefreshToken => secret.from_refresh(decoded, None, None, json.clone()),
+        // A newly issued token replaces the previous one outright, and the client
+        // credentials it was requested with are all that carries over.
+        Grant::ClientCredentials { .. } => OAuthSecret {
+            access_token: decoded.access_token,
+            token_type: decoded.token_type,
+            refresh_token: None,
+            expires_in: decoded.expires_in,
+            metadata: json.clone(),
+            ..secret.clone()
+        },
+    };
+
+    let ConnectionExpiry {
+        expires_in,
+        expires_at,
+        policy,
+    } = connection_expiry(
+        &cached_definition,
+        &json,
+        &oauth_secret,
+        sent_at,
+        &oauths,
+        &client,
+        msg.connection(),
+    )
+    .await;
 
     let set = OAuth::Enabled {
         connection_oauth_definition_id: *conn_oauth_id,
-        expires_at: Some(
-            (chrono::Utc::now() + Duration::seconds(oauth_secret.expires_in as i64)).timestamp(),
-        ),
-        expires_in: Some(oauth_secret.expires_in),
+        expires_at,
+        expires_in,
     };
 
-    let data = doc! {
-        "$set": {
-            "oauth": bson::to_bson(&set).map_err(|e| {
-                warn!("Failed to serialize oauth: {}", e);
-                InternalError::serialize_error("Failed to serialize oauth", None)
-            })?,
-            "secretsServiceId": secret.id(),
-        }
+    // Validation runs before anything is stored, but a token that fails it is still
+    // persisted, since the provider may already have invalidated the previous one.
+    let validated = match cached_definition.validation() {
+        Some(validation) => Some(validate(validation, &oauth_secret, &client).await),
+        None => None,
     };
 
-    connections
-        .update_one(&msg.connection().id.to_string(), data)
-        .await
-        .map_err(|e| {
-            warn!("Failed to update connection: {}", e);
-            InternalError::io_err("Failed to update connection", None)
-        })?;
+    // A JWT policy whose token carries no `exp` would otherwise pick the connection
+    // up again on every cycle, so it keeps backing off like any other failure.
+    let unscheduled = expires_at.is_none() && matches!(policy, Some(ExpiryPolicy::Jwt { .. }));
+
+    // The rotated credentials are staged in the outbox before the secrets service is
+    // called, so they survive a failed write and are completed by a later attempt.
+    let entry = secrets
+        .outbox()
+        .seal(msg.connection(), &oauth_secret.as_json(), set, !unscheduled)
+        .await?;
+    stage(&entry, &secrets, msg.connection()).await?;
+
+    complete(&entry, &secrets, &connections).await?;
 
     tracing::info!("Connection {} updated", msg.connection().id);
 
-    Ok(Refreshed::new(
-        msg.connection().id.to_string().as_str(),
-        json!({ "id": msg.connection().id.to_string() }),
-    ))
+    if let Some(validated) = validated {
+        let error = validated.as_ref().err().map(ToString::to_string);
+        if let Err(e) = connections
+            .record_validation(&msg.connection().id, error.as_deref())
+            .await
+        {
+            warn!(
+                "Failed to record validation of connection {}: {}",
+                msg.connection().id,
+                e
+            );
+        }
+        validated?;
+    }
+
+    if unscheduled {
+        return Err(InternalError::invalid_argument(
+            "Access token carries no exp claim to schedule the next refresh",
+            None,
+        )
+        .into());
+    }
+
+    Ok(refreshed(msg.connection()))
+}
+
+/// JSON body of a successful token endpoint response. Error responses, including
+/// ones sent with a success status, are turned into a `RefreshError` that tells
+/// rejected grants apart from transient failures.
+pub async fn token_response(
+    response: reqwest::Response,
+    action: &str,
+    connection: &Connection,
+) -> Result<serde_json::Value, RefreshError> {
+    let status = response.status();
+    if !status.is_success() {
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+
+        if let Some(error) = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .as_ref()
+            .and_then(OAuthError::from_response)
+        {
+            warn!(
+                "Provider rejected {} of connection {}: {}",
+                action, connection.id, error
+            );
+            return Err(RefreshError::OAuth(error));
+        }
+
+        let error = RefreshError::status(
+            status.as_u16(),
+            headers
+                .iter()
+                .filter_map(|(key, value)| Some((key.as_str(), value.to_str().ok()?))),
+            &body,
+        );
+        warn!(
+            "Token request for {} of connection {} failed: {}",
+            action, connection.id, error
+        );
+        return Err(error);
+    }
+
+    let json = response.json::<serde_json::Value>().await.map_err(|e| {
+        warn!("Failed to parse response: {}", e);
+        InternalError::deserialize_error("Failed to parse response", None)
+    })?;
+
+    if let Some(error) = OAuthError::from_response(&json) {
+        warn!(
+            "Provider rejected {} of connection {}: {}",
+            action, connection.id, error
+        );
+        return Err(RefreshError::OAuth(error));
+    }
+
+    Ok(json)
+}
+
+/// Expiry of a connection whose token endpoint answered `json` to a request sent
+/// at `sent_at`. Definitions with a JWT expiry policy trust the claims of the chosen
+/// token over the response. Providers that report no expiry at all leave the
+/// decision of when to refresh the connection next to the expiry policy.
+pub async fn connection_expiry(
+    cached_definition: &CachedDefinition,
+    json: &serde_json::Value,
+    oauth_secret: &OAuthSecret,
+    sent_at: DateTime<Utc>,
+    oauths: &DefinitionCache,
+    client: &ClientWithMiddleware,
+    connection: &Connection,
+) -> ConnectionExpiry {
+    let reported_expires_at = normalize_expires_at(json, sent_at);
+    let id_token = json.get("id_token").and_then(serde_json::Value::as_str);
+
+    let skew = cached_definition.expiry_skew_in_seconds();
+    let policy = cached_definition.expiry_policy();
+    let jwt_reported_expires_at = match policy {
+        ExpiryPolicy::Jwt { source, jwks_uri } => {
+            let token = match source {
+                JwtSource::AccessToken => Some(oauth_secret.access_token.as_str()),
+                JwtSource::IdToken => id_token,
+            };
+            match token {
+                Some(token) => {
+                    read_jwt_expiry(
+                        token,
+                        jwks_uri.as_deref(),
+                        oauths,
+                        client,
+                        sent_at,
+                        connection,
+                    )
+                    .await
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    };
+    let reported_expires_at = jwt_reported_expires_at.or(reported_expires_at).or_else(|| {
+        (oauth_secret.expires_in > 0)
+            .then(|| (sent_at + Duration::seconds(oauth_secret.expires_in as i64)).timestamp())
+    });
+    let (expires_in, expires_at, policy) = match reported_expires_at {
+        Some(expires_at) => (
+            i32::try_from(expires_at - sent_at.timestamp()).ok(),
+            Some(expires_at - skew),
+            None,
+        ),
+        None => (None, policy.expires_at(sent_at), Some(policy.clone())),
+    };
+
+    ConnectionExpiry {
+        expires_in,
+        expires_at,
+        policy,
+    }
+}
+
+/// Expiry of the connection according to the claims of `token`. Tokens that fail
+/// verification are ignored rather than failing the refresh, since the rotated
+/// credentials they came with still have to be persisted.
+async fn read_jwt_expiry(
+    token: &str,
+    jwks_uri: Option<&str>,
+    oauths: &DefinitionCache,
+    client: &ClientWithMiddleware,
+    received_at: DateTime<Utc>,
+    connection: &Connection,
+) -> Option<i64> {
+    let Some(jwks_uri) = jwks_uri else {
+        return jwt_claims(token)?.expires_at(received_at);
+    };
+
+    let mut claims = Ok(None);
+    // A key that is missing from the cached JWKS may have been rotated in since.
+    for refetch in [false, true] {
+        claims = match oauths.jwks(jwks_uri, client, refetch).await {
+            Ok(jwks) => verified_jwt_claims(token, &jwks),
+            Err(e) => Err(e),
+        };
+        if !matches!(claims, Ok(None)) {
+            break;
+        }
+    }
+
+    match claims {
+        Ok(Some(claims)) => claims.expires_at(received_at),
+        Ok(None) => {
+            warn!(
+                "No JWKS key matches the token of connection {}",
+                connection.id
+            );
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Failed to verify the token of connection {}: {}",
+                connection.id, e
+            );
+            None
+        }
+    }
 }
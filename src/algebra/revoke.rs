@@ -0,0 +1,212 @@
+use crate::{
+    algebra::{authenticate_client, DefinitionCache, DefinitionStorageExt, StorageExt},
+    domain::{OAuthError, Refresh, RefreshError, RevocationOutcome, TokenTypeHint, Unit},
+    SecretsClient,
+};
+use osentities::{
+    algebra::MongoStore, error::PicaError as Error, oauth_secret::OAuthSecret, ApplicationError,
+    Connection, InternalError, OAuth,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tracing::warn;
+
+/// Failed revocations of a connection after which the sweeper gives up on it.
+pub const REVOCATION_MAX_ATTEMPTS: u32 = 5;
+
+/// Revokes the tokens of `connection` at the provider as described in RFC 7009,
+/// using the `revocation` section of its connection oauth definition.
+pub async fn revoke(
+    connection: &Connection,
+    secrets: &SecretsClient,
+    oauths: &DefinitionCache,
+    client: &ClientWithMiddleware,
+) -> Result<RevocationOutcome, RefreshError> {
+    let conn_oauth_id = match &connection.oauth {
+        Some(OAuth::Enabled {
+            connection_oauth_definition_id,
+            ..
+        }) => connection_oauth_definition_id,
+        _ => {
+            return Err(ApplicationError::not_found(
+                &format!("Connection {} has no oauth", connection.id),
+                None,
+            )
+            .into())
+        }
+    };
+
+    let Some(revocation) = oauths.store().get_revocation(conn_oauth_id).await? else {
+        return Ok(RevocationOutcome::Unsupported);
+    };
+
+    let secret: OAuthSecret = secrets
+        .get_secret::<OAuthSecret>(
+            &connection.secrets_service_id,
+            &connection.ownership.client_id,
+            &connection.environment,
+        )
+        .await?;
+
+    // Connections without a refresh token can only have their access token revoked.
+    let (token, token_type_hint) = match (revocation.token_type_hint, &secret.refresh_token) {
+        (TokenTypeHint::RefreshToken, Some(refresh_token)) => {
+            (refresh_token.as_str(), TokenTypeHint::RefreshToken)
+        }
+        _ => (secret.access_token.as_str(), TokenTypeHint::AccessToken),
+    };
+
+    let mut form = BTreeMap::from([
+        ("token", token),
+        (
+            "token_type_hint",
+            match token_type_hint {
+                TokenTypeHint::RefreshToken => "refresh_token",
+                TokenTypeHint::AccessToken => "access_token",
+            },
+        ),
+    ]);
+
+    let request = authenticate_client(
+        client.post(&revocation.uri),
+        &mut form,
+        revocation.auth_method,
+        &secret,
+    );
+
+    let response = request.form(&form).send().await.map_err(|e| {
+        warn!("Failed to execute request: {}", e);
+        InternalError::io_err("Failed to execute request", None)
+    })?;
+
+    // The provider answers 200 for tokens that were already invalid, so revoking
+    // twice is harmless.
+    let status = response.status();
+    if !status.is_success() {
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+
+        let error = match serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .as_ref()
+            .and_then(OAuthError::from_response)
+        {
+            Some(error) => RefreshError::OAuth(error),
+            None => RefreshError::status(
+                status.as_u16(),
+                headers
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.as_str(), value.to_str().ok()?))),
+                &body,
+            ),
+        };
+        warn!(
+            "Failed to revoke tokens of connection {}: {}",
+            connection.id, error
+        );
+        return Err(error);
+    }
+
+    tracing::info!("Revoked tokens of connection {}", connection.id);
+
+    Ok(RevocationOutcome::Revoked)
+}
+
+/// Revokes the tokens of `connection` and records the outcome on it, so the
+/// sweeper does not pick it up again. Only deleted connections are revoked, as
+/// live ones would keep being refreshed with tokens that no longer work.
+pub async fn revoke_connection(
+    connection: &Connection,
+    secrets: &SecretsClient,
+    connections: &MongoStore<Connection>,
+    oauths: &DefinitionCache,
+    client: &ClientWithMiddleware,
+) -> Result<RevocationOutcome, RefreshError> {
+    if !connection.record_metadata.deleted {
+        return Err(ApplicationError::bad_request(
+            &format!(
+                "Connection {} is not deleted, delete it before revoking its tokens",
+                connection.id
+            ),
+            None,
+        )
+        .into());
+    }
+
+    let result = revoke(connection, secrets, oauths, client).await;
+
+    let recorded = match &result {
+        Ok(outcome) => {
+            connections
+                .record_revocation(&connection.id, Ok(*outcome))
+                .await
+        }
+        Err(e) => {
+            connections
+                .record_revocation(&connection.id, Err(&e.to_string()))
+                .await
+        }
+    };
+    if let Err(e) = recorded {
+        warn!(
+            "Failed to record revocation of connection {}: {}",
+            connection.id, e
+        );
+    }
+
+    result
+}
+
+/// Revokes the tokens of deleted connections every `interval` until shutdown.
+pub async fn sweep_revocations(
+    msg: Refresh,
+    secrets: Arc<SecretsClient>,
+    connections: Arc<MongoStore<Connection>>,
+    oauths: Arc<DefinitionCache>,
+    client: ClientWithMiddleware,
+    interval: Duration,
+    limit: u64,
+) -> Result<Unit, Error> {
+    if msg.dry_run() {
+        tracing::info!("Revocation sweeper disabled during dry run");
+        return Ok(());
+    }
+
+    loop {
+        match connections
+            .get_unrevoked(REVOCATION_MAX_ATTEMPTS, limit)
+            .await
+        {
+            Ok(deleted) => {
+                for connection in deleted {
+                    if msg.shutdown().is_triggered() {
+                        break;
+                    }
+
+                    match revoke_connection(&connection, &secrets, &connections, &oauths, &client)
+                        .await
+                    {
+                        Ok(RevocationOutcome::Unsupported) => tracing::debug!(
+                            "Definition of connection {} has no revocation endpoint",
+                            connection.id
+                        ),
+                        Ok(RevocationOutcome::Revoked) => {}
+                        Err(e) => warn!(
+                            "Failed to revoke tokens of deleted connection {}: {}",
+                            connection.id, e
+                        ),
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to read deleted connections: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = msg.shutdown().triggered() => {
+                tracing::info!("Revocation sweeper stopped");
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    algebra::{
+        expires_at, refresh_connections, DefinitionCache, DefinitionStorageExt, StorageExt,
+        WITHOUT_EXPIRY_LIMIT,
+    },
+    domain::{Refresh, Schedule, Unit},
+    Metrics, SecretsClient,
+};
+use chrono::{DateTime, Utc};
+use osentities::{algebra::MongoStore, error::PicaError as Error, Connection};
+use reqwest_middleware::ClientWithMiddleware;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::Instant};
+
+/// Longest the scheduler sleeps before recording a heartbeat, so an idle
+/// scheduler with a long reconcile interval still counts as live.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Refreshes every connection at the moment it enters the `refresh_before` window.
+/// Upcoming expiries are loaded into a time-ordered queue every reconcile interval
+/// and the loop sleeps until the next one is due. Connections received on
+/// `changes` are scheduled as they arrive.
+pub async fn schedule(
+    msg: Refresh,
+    mut changes: mpsc::Receiver<Connection>,
+    connections_store: Arc<MongoStore<Connection>>,
+    secrets: Arc<SecretsClient>,
+    oauths: Arc<DefinitionCache>,
+    client: ClientWithMiddleware,
+    metrics: Arc<Metrics>,
+) -> Result<Unit, Error> {
+    let msg = msg.with_heartbeat();
+    let reconcile_interval = Duration::from_secs(msg.reconcile_interval_in_seconds());
+    let mut schedule = Schedule::new();
+    let mut next_reconcile = Instant::now();
+
+    loop {
+        metrics.record_heartbeat();
+
+        if msg.shutdown().is_triggered() {
+            tracing::info!("Scheduler stopped");
+            return Ok(());
+        }
+
+        if Instant::now() >= next_reconcile {
+            match reconcile(&msg, &mut schedule, &connections_store, &oauths).await {
+                Ok(()) => metrics.record_cycle_completed(),
+                Err(e) => tracing::error!("Failed to reconcile refresh schedule: {:?}", e),
+            }
+            next_reconcile = Instant::now() + reconcile_interval;
+        }
+
+        let due = schedule.pop_due(Utc::now().timestamp());
+        if !due.is_empty() {
+            tracing::info!("{} connections are due for refresh", due.len());
+            // The next reconciliation schedules the connections again, so a failed
+            // batch does not stop the scheduler.
+            match refresh_connections(
+                &msg,
+                due,
+                connections_store.clone(),
+                secrets.clone(),
+                oauths.clone(),
+                client.clone(),
+                metrics.clone(),
+            )
+            .await
+            {
+                Ok(_) => metrics.record_cycle_completed(),
+                Err(e) => tracing::error!("Failed to refresh due connections: {:?}", e),
+            }
+            continue;
+        }
+
+        let wake = schedule
+            .next_due()
+            .map(|due_at| {
+                let wait = (due_at - Utc::now().timestamp()).max(0) as u64;
+                Instant::now() + Duration::from_secs(wait)
+            })
+            .map_or(next_reconcile, |wake| wake.min(next_reconcile))
+            .min(Instant::now() + HEARTBEAT_INTERVAL);
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake) => {}
+            _ = msg.shutdown().triggered() => {}
+            Some(connection) = changes.recv() => {
+                let due_at = scheduled_at(&msg, &connection, Utc::now().timestamp());
+                schedule.insert(due_at, connection);
+            }
+        }
+    }
+}
+
+/// Replaces the schedule with the connections expiring before the next
+/// reconciliation, each due `refresh_before` minutes ahead of its expiry.
+async fn reconcile(
+    msg: &Refresh,
+    schedule: &mut Schedule,
+    connections_store: &MongoStore<Connection>,
+    oauths: &DefinitionCache,
+) -> Result<Unit, Error> {
+    let now = Utc::now();
+    let horizon = horizon(msg, &now);
+
+    let upcoming = connections_store.get_by(&now, &horizon).await?;
+    let definition_ids = oauths.store().get_with_expiry_policy().await?;
+    let without_expiry = connections_store
+        .get_without_expiry(&definition_ids, WITHOUT_EXPIRY_LIMIT)
+        .await?;
+
+    schedule.clear();
+    for connection in upcoming.into_iter().chain(without_expiry) {
+        schedule.insert(scheduled_at(msg, &connection, now.timestamp()), connection);
+    }
+
+    tracing::info!(
+        "Scheduled {} connections expiring before {}",
+        schedule.len(),
+        horizon.timestamp()
+    );
+
+    Ok(())
+}
+
+/// Latest expiry that has to be scheduled before the next reconciliation.
+pub fn horizon(msg: &Refresh, now: &DateTime<Utc>) -> DateTime<Utc> {
+    *now + chrono::Duration::minutes(msg.refresh_before_in_minutes())
+        + chrono::Duration::seconds(msg.reconcile_interval_in_seconds() as i64)
+}
+
+/// Connections without an `expires_at` only reach the schedule when the expiry
+/// policy of their definition refreshes them, so they are due immediately.
+fn scheduled_at(msg: &Refresh, connection: &Connection, now: i64) -> i64 {
+    expires_at(connection).map_or(now, |expires_at| {
+        expires_at - msg.refresh_before_in_minutes() * 60
+    })
+}
@@ -1,25 +1,36 @@
-use crate::RefreshConfig;
+use crate::{Outbox, RefreshConfig};
 use mongodb::bson::doc;
 use osentities::{
     environment::Environment, event_access::EventAccess, InternalError, MongoStore, PicaError,
     Secret,
 };
+use reqwest::StatusCode;
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 use tracing::warn;
 
 const PRODUCTION_KEY: &str = "event_access::custom::live::default::event-inc::internal-ui";
 const TEST_KEY: &str = "event_access::custom::test::default::event-inc::internal-ui";
 const PICA_SECRET_HEADER: &str = "X-PICA-SECRET";
 
+type AccessKeys = HashMap<(String, Environment), (String, Instant)>;
+
 #[derive(Debug, Clone)]
 pub struct SecretsClient {
     get: String,
     create: String,
+    delete: String,
     client: ClientWithMiddleware,
     event: Arc<MongoStore<EventAccess>>,
+    access_keys: Arc<RwLock<AccessKeys>>,
+    access_key_ttl: Duration,
+    outbox: Arc<Outbox>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,37 +44,32 @@ impl SecretsClient {
         config: &RefreshConfig,
         event: &Arc<MongoStore<EventAccess>>,
         client: ClientWithMiddleware,
+        outbox: Outbox,
     ) -> Self {
         Self {
             get: config.get_secret().to_string(),
             create: config.create_secret().to_string(),
+            delete: config.delete_secret().to_string(),
             client,
             event: Arc::clone(event),
+            access_keys: Arc::new(RwLock::new(HashMap::new())),
+            access_key_ttl: Duration::from_secs(config.event_access_cache_ttl()),
+            outbox: Arc::new(outbox),
         }
     }
 
+    /// Refreshed secrets waiting to be written through this client.
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
     pub async fn get_secret<T: for<'a> Deserialize<'a>>(
         &self,
         id: &str,
         buildable_id: &str,
         environment: &Environment,
     ) -> Result<T, PicaError> {
-        let key = match environment {
-            Environment::Test | Environment::Development => TEST_KEY,
-            Environment::Live | Environment::Production => PRODUCTION_KEY,
-        };
-
-        let event = self
-            .event
-            .get_one(doc! {
-                "ownership.buildableId": buildable_id,
-                "key": key,
-                "deleted": false
-            })
-            .await?
-            .ok_or(InternalError::key_not_found("Event access not found", None))?;
-
-        let access_key = event.access_key.clone();
+        let access_key = self.access_key(buildable_id, environment).await?;
 
         let uri = format!("{}/{}", self.get, id);
         let response = self
@@ -76,6 +82,8 @@ impl SecretsClient {
                 InternalError::io_err(&format!("Failed to send request: {err}"), None)
             })?;
 
+        self.check_rejected(response.status(), buildable_id, environment)?;
+
         let secret = response.json().await;
 
         let secret: Secret = secret.map_err(|err| {
@@ -99,21 +107,7 @@ impl SecretsClient {
             })?,
         };
 
-        let key = match environment {
-            Environment::Test | Environment::Development => TEST_KEY,
-            Environment::Live | Environment::Production => PRODUCTION_KEY,
-        };
-
-        let event = self
-            .event
-            .get_one(doc! {
-                "ownership.buildableId": buildable_id,
-                "key": key
-            })
-            .await?
-            .ok_or(InternalError::key_not_found("Event access not found", None))?;
-
-        let access_key = event.access_key.clone();
+        let access_key = self.access_key(&buildable_id, &environment).await?;
 
         let response = self
             .client
@@ -126,9 +120,117 @@ impl SecretsClient {
                 InternalError::io_err(&format!("Failed to send request: {err}"), None)
             })?;
 
+        self.check_rejected(response.status(), &buildable_id, &environment)?;
+
         response.json().await.map_err(|err| {
             warn!("Failed to deserialize response: {err}");
             InternalError::serialize_error(&format!("Failed to deserialize response: {err}"), None)
         })
     }
+
+    /// Deletes a secret that is no longer referenced by any connection.
+    pub async fn delete_secret(
+        &self,
+        id: &str,
+        buildable_id: &str,
+        environment: &Environment,
+    ) -> Result<(), PicaError> {
+        let access_key = self.access_key(buildable_id, environment).await?;
+
+        let uri = format!("{}/{}", self.delete, id);
+        let response = self
+            .client
+            .delete(&uri)
+            .header(PICA_SECRET_HEADER, access_key)
+            .send()
+            .await
+            .map_err(|err| {
+                InternalError::io_err(&format!("Failed to send request: {err}"), None)
+            })?;
+
+        self.check_rejected(response.status(), buildable_id, environment)?;
+
+        let status = response.status();
+        if !status.is_success() && status != StatusCode::NOT_FOUND {
+            return Err(InternalError::io_err(
+                &format!("Failed to delete secret {id}: status {status}"),
+                None,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the secrets service answers without a server error. Client errors
+    /// are expected, since no secret is asked for.
+    pub async fn is_reachable(&self) -> bool {
+        self.client
+            .get(&self.get)
+            .send()
+            .await
+            .is_ok_and(|response| !response.status().is_server_error())
+    }
+
+    /// Access key of the internal event access for `buildable_id`, cached for
+    /// `EVENT_ACCESS_CACHE_TTL_IN_SECONDS`.
+    async fn access_key(
+        &self,
+        buildable_id: &str,
+        environment: &Environment,
+    ) -> Result<String, PicaError> {
+        let cache_key = (buildable_id.to_string(), *environment);
+
+        let cached = self.access_keys.read().ok().and_then(|access_keys| {
+            access_keys
+                .get(&cache_key)
+                .filter(|(_, fetched_at)| fetched_at.elapsed() < self.access_key_ttl)
+                .map(|(access_key, _)| access_key.clone())
+        });
+        if let Some(access_key) = cached {
+            return Ok(access_key);
+        }
+
+        let key = match environment {
+            Environment::Test | Environment::Development => TEST_KEY,
+            Environment::Live | Environment::Production => PRODUCTION_KEY,
+        };
+
+        let event = self
+            .event
+            .get_one(doc! {
+                "ownership.buildableId": buildable_id,
+                "key": key,
+                "deleted": false,
+            })
+            .await?
+            .ok_or(InternalError::key_not_found("Event access not found", None))?;
+
+        if let Ok(mut access_keys) = self.access_keys.write() {
+            access_keys.insert(cache_key, (event.access_key.clone(), Instant::now()));
+        }
+
+        Ok(event.access_key)
+    }
+
+    /// Drops the cached access key when the secrets service rejects it, so a
+    /// rotated key is picked up on the next call instead of after the TTL.
+    fn check_rejected(
+        &self,
+        status: StatusCode,
+        buildable_id: &str,
+        environment: &Environment,
+    ) -> Result<(), PicaError> {
+        if status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN {
+            return Ok(());
+        }
+
+        if let Ok(mut access_keys) = self.access_keys.write() {
+            access_keys.remove(&(buildable_id.to_string(), *environment));
+        }
+
+        Err(InternalError::invalid_argument(
+            &format!("Secrets service rejected the access key with status {status}"),
+            None,
+        ))
+    }
 }
@@ -1,7 +1,49 @@
+use crate::{Backoff, DefinitionSettings, Lease, RefreshState, Revocation, RevocationOutcome};
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
-use mongodb::bson::doc;
-use osentities::{Connection, Id, MongoStore, PicaError};
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, Document},
+    change_stream::{
+        event::{ChangeStreamEvent, ResumeToken},
+        ChangeStream,
+    },
+    error::{Error as MongoError, ErrorKind},
+    options::{FullDocumentType, ReturnDocument},
+};
+use osentities::{
+    connection_oauth_definition::ConnectionOAuthDefinition, Connection, Id, InternalError,
+    MongoStore, OAuth, PicaError,
+};
+use serde::Deserialize;
+
+pub const REFRESH_LEASE_FIELD: &str = "refreshLease";
+pub const REFRESH_FAILURE_FIELD: &str = "refreshFailure";
+pub const REAUTHORIZATION_REQUIRED_FIELD: &str = "reauthorizationRequired";
+pub const REAUTHORIZATION_SECRET_FIELD: &str = "reauthorizationSecretsServiceId";
+pub const LAST_REFRESHED_AT_FIELD: &str = "lastRefreshedAt";
+pub const EXPIRY_POLICY_FIELD: &str = "expiryPolicy";
+pub const EXPIRY_SKEW_FIELD: &str = "expirySkewInSeconds";
+pub const REVOCATION_FIELD: &str = "revocation";
+pub const REVOKED_AT_FIELD: &str = "revokedAt";
+pub const REVOCATION_FAILURE_FIELD: &str = "revocationFailure";
+pub const REVOCATION_UNSUPPORTED_FIELD: &str = "revocationUnsupportedAt";
+pub const VALIDATION_FIELD: &str = "validation";
+pub const GRANT_FIELD: &str = "grant";
+pub const TOKEN_VALIDATION_FIELD: &str = "tokenValidation";
+
+/// Server error codes reported when the deployment cannot open change streams,
+/// such as a standalone server (40573) or one predating them (40324).
+const CHANGE_STREAMS_UNSUPPORTED: [i32; 2] = [40573, 40324];
+
+/// Whether `e` means change streams are unsupported by the deployment rather than
+/// temporarily unavailable.
+pub fn change_streams_unsupported(e: &MongoError) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Command(e) if CHANGE_STREAMS_UNSUPPORTED.contains(&e.code)
+    )
+}
 
 #[async_trait]
 pub trait StorageExt {
@@ -11,7 +53,135 @@ pub trait StorageExt {
         refresh_after: &DateTime<Utc>,
     ) -> Result<Vec<Connection>, PicaError>;
 
+    /// Returns at most `limit` connections whose tokens expired between
+    /// `expired_after` and `expired_before`, most recently expired first.
+    async fn get_expired(
+        &self,
+        expired_after: &DateTime<Utc>,
+        expired_before: &DateTime<Utc>,
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError>;
+
+    /// Returns at most `limit` connections without an `expires_at` that belong to
+    /// one of `definition_ids`.
+    async fn get_without_expiry(
+        &self,
+        definition_ids: &[String],
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError>;
+
     async fn get(&self, id: Id) -> Result<Option<Connection>, PicaError>;
+
+    async fn get_refresh_state(&self, id: &Id) -> Result<Option<RefreshState>, PicaError>;
+
+    /// Opens a change stream over inserted and replaced connections and updates
+    /// that touch `oauth`, resuming after `resume_after` when given.
+    async fn watch_oauth(
+        &self,
+        resume_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<Connection>>, MongoError>;
+
+    /// Atomically claims the connection for `lease.owner()`. Returns `false` when
+    /// any lease on it, including one of this replica, has not yet expired, or
+    /// when the connection was deleted.
+    async fn claim(&self, id: &Id, lease: &Lease) -> Result<bool, PicaError>;
+
+    /// Applies `update` only while the connection still references
+    /// `secrets_service_id`, or already references `staged_secret_id` because an
+    /// earlier attempt applied it. Returns `false` when the connection was
+    /// reauthorized in the meantime.
+    async fn update_if_unchanged(
+        &self,
+        id: &Id,
+        secrets_service_id: &str,
+        staged_secret_id: &str,
+        update: Document,
+    ) -> Result<bool, PicaError>;
+
+    async fn release(&self, id: &Id, lease: &Lease) -> Result<(), PicaError>;
+
+    /// Increments the failure count of the connection and pushes its next attempt
+    /// out by `backoff`, doubling for every consecutive failure.
+    async fn record_failure(
+        &self,
+        id: &Id,
+        error: &str,
+        backoff: &Backoff,
+    ) -> Result<(), PicaError>;
+
+    /// Flags the connection as requiring the end user to authorize it again. Such
+    /// connections are no longer picked up for refresh until their secret, the one
+    /// `secrets_service_id` names, is replaced.
+    async fn require_reauthorization(
+        &self,
+        id: &Id,
+        secrets_service_id: &str,
+        error: &str,
+    ) -> Result<(), PicaError>;
+
+    /// Stores the outcome of validating the refreshed token of the connection, which
+    /// failed with `error` when given.
+    async fn record_validation(&self, id: &Id, error: Option<&str>) -> Result<(), PicaError>;
+
+    /// Returns at most `limit` deleted oauth connections whose tokens have not been
+    /// revoked yet, skipping those that failed `max_attempts` times.
+    async fn get_unrevoked(
+        &self,
+        max_attempts: u32,
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError>;
+
+    /// Marks the tokens of the connection as revoked or as not revocable by its
+    /// provider, or counts a failed attempt with the given error.
+    async fn record_revocation(
+        &self,
+        id: &Id,
+        outcome: Result<RevocationOutcome, &str>,
+    ) -> Result<(), PicaError>;
+
+    /// Stores the credentials of a new authorization on the connection and clears
+    /// any refresh failure or pending reauthorization. Returns the connection as it
+    /// was before, or `None` when it does not exist.
+    async fn authorize(
+        &self,
+        id: &Id,
+        oauth: &OAuth,
+        secrets_service_id: &str,
+    ) -> Result<Option<Connection>, PicaError>;
+}
+
+/// Excludes connections that are deleted, leased by another replica, backing off
+/// after a failure or waiting for the end user to authorize them again. Deleted
+/// connections must not mint tokens the revocation sweeper never sees.
+///
+/// The reauthorization flag only holds for the secret that failed; a connection
+/// reconnected outside this service has a new one and is refreshed again.
+fn refreshable(now: &DateTime<Utc>) -> Document {
+    let now = now.timestamp();
+
+    doc! {
+        "deleted": { "$ne": true },
+        "$and": [
+            { "$or": [
+                { REAUTHORIZATION_REQUIRED_FIELD: { "$ne": true } },
+                { "$expr": { "$ne": [
+                    { "$ifNull": [
+                        format!("${REAUTHORIZATION_SECRET_FIELD}"),
+                        "$secretsServiceId",
+                    ] },
+                    "$secretsServiceId",
+                ] } },
+            ] },
+            { "$or": [
+                { REFRESH_LEASE_FIELD: { "$exists": false } },
+                { format!("{REFRESH_LEASE_FIELD}.expiresAt"): { "$lt": now } },
+            ] },
+            { "$or": [
+                { format!("{REFRESH_FAILURE_FIELD}.nextAttemptAt"): { "$exists": false } },
+                { format!("{REFRESH_FAILURE_FIELD}.nextAttemptAt"): { "$lte": now } },
+            ] },
+        ],
+    }
 }
 
 #[async_trait]
@@ -21,25 +191,420 @@ impl StorageExt for MongoStore<Connection> {
         refresh_before: &DateTime<Utc>,
         refresh_after: &DateTime<Utc>,
     ) -> Result<Vec<Connection>, PicaError> {
+        let mut filter = doc! {
+            "oauth.enabled.expires_at": doc! {
+                "$gte": refresh_before.timestamp(),
+                "$lte": refresh_after.timestamp(),
+            },
+        };
+        filter.extend(refreshable(&Utc::now()));
+
+        self.get_many(Some(filter), None, None, None, None).await
+    }
+
+    async fn get_expired(
+        &self,
+        expired_after: &DateTime<Utc>,
+        expired_before: &DateTime<Utc>,
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError> {
+        let mut filter = doc! {
+            "oauth.enabled.expires_at": doc! {
+                "$gte": expired_after.timestamp(),
+                "$lt": expired_before.timestamp(),
+            },
+        };
+        filter.extend(refreshable(&Utc::now()));
+
         self.get_many(
-            Some(doc! {
-                "oauth.enabled.expires_at": doc! {
-                    "$gte": refresh_before.timestamp(),
-                    "$lte": refresh_after.timestamp(),
-                },
-            }),
-            None,
-            None,
+            Some(filter),
             None,
+            Some(doc! { "oauth.enabled.expires_at": -1 }),
+            Some(limit),
             None,
         )
         .await
     }
 
+    async fn get_without_expiry(
+        &self,
+        definition_ids: &[String],
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError> {
+        if definition_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut filter = doc! {
+            "oauth.enabled": { "$exists": true },
+            "oauth.enabled.expires_at": null,
+            "oauth.enabled.connection_oauth_definition_id": { "$in": definition_ids },
+        };
+        filter.extend(refreshable(&Utc::now()));
+
+        self.get_many(Some(filter), None, None, Some(limit), None)
+            .await
+    }
+
     async fn get(&self, id: Id) -> Result<Option<Connection>, PicaError> {
         self.get_one(doc! {
             "_id": id.to_string(),
         })
         .await
     }
+
+    async fn get_refresh_state(&self, id: &Id) -> Result<Option<RefreshState>, PicaError> {
+        Ok(self
+            .collection
+            .clone_with_type::<RefreshState>()
+            .find_one(doc! { "_id": id.to_string() })
+            .projection(doc! {
+                "oauth": 1,
+                REFRESH_FAILURE_FIELD: 1,
+                REFRESH_LEASE_FIELD: 1,
+                REAUTHORIZATION_REQUIRED_FIELD: 1,
+                REAUTHORIZATION_SECRET_FIELD: 1,
+                "secretsServiceId": 1,
+                LAST_REFRESHED_AT_FIELD: 1,
+                TOKEN_VALIDATION_FIELD: 1,
+            })
+            .await?)
+    }
+
+    async fn watch_oauth(
+        &self,
+        resume_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<Connection>>, MongoError> {
+        // Updates may set `oauth` as a whole or only some of its members, such as
+        // `oauth.expires_at`, so any updated path under it counts.
+        let pipeline = vec![doc! {
+            "$match": {
+                "$or": [
+                    { "operationType": { "$in": ["insert", "replace"] } },
+                    {
+                        "operationType": "update",
+                        "$expr": {
+                            "$gt": [
+                                {
+                                    "$size": {
+                                        "$filter": {
+                                            "input": {
+                                                "$objectToArray": "$updateDescription.updatedFields"
+                                            },
+                                            "cond": {
+                                                "$regexMatch": {
+                                                    "input": "$$this.k",
+                                                    "regex": "^oauth(\\.|$)",
+                                                }
+                                            },
+                                        }
+                                    }
+                                },
+                                0,
+                            ]
+                        },
+                    },
+                ]
+            }
+        }];
+
+        self.collection
+            .watch()
+            .pipeline(pipeline)
+            .full_document(FullDocumentType::UpdateLookup)
+            .resume_after(resume_after)
+            .await
+    }
+
+    async fn claim(&self, id: &Id, lease: &Lease) -> Result<bool, PicaError> {
+        let now = Utc::now();
+        let expires_at = now + Duration::seconds(lease.duration_in_seconds());
+
+        let result = self
+            .collection
+            .update_one(
+                doc! {
+                    "_id": id.to_string(),
+                    "deleted": { "$ne": true },
+                    "$or": [
+                        { REFRESH_LEASE_FIELD: { "$exists": false } },
+                        { format!("{REFRESH_LEASE_FIELD}.expiresAt"): { "$lt": now.timestamp() } },
+                    ],
+                },
+                doc! {
+                    "$set": {
+                        REFRESH_LEASE_FIELD: {
+                            "owner": lease.owner(),
+                            "expiresAt": expires_at.timestamp(),
+                        }
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.matched_count == 1)
+    }
+
+    async fn update_if_unchanged(
+        &self,
+        id: &Id,
+        secrets_service_id: &str,
+        staged_secret_id: &str,
+        update: Document,
+    ) -> Result<bool, PicaError> {
+        let result = self
+            .collection
+            .update_one(
+                doc! {
+                    "_id": id.to_string(),
+                    "secretsServiceId": { "$in": [secrets_service_id, staged_secret_id] },
+                },
+                update,
+            )
+            .await?;
+
+        Ok(result.matched_count == 1)
+    }
+
+    async fn release(&self, id: &Id, lease: &Lease) -> Result<(), PicaError> {
+        self.collection
+            .update_one(
+                doc! {
+                    "_id": id.to_string(),
+                    format!("{REFRESH_LEASE_FIELD}.owner"): lease.owner(),
+                },
+                doc! {
+                    "$unset": { REFRESH_LEASE_FIELD: "" }
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn record_failure(
+        &self,
+        id: &Id,
+        error: &str,
+        backoff: &Backoff,
+    ) -> Result<(), PicaError> {
+        let now = Utc::now().timestamp();
+        let count = format!("${REFRESH_FAILURE_FIELD}.count");
+
+        let pipeline = vec![
+            doc! {
+                "$set": {
+                    format!("{REFRESH_FAILURE_FIELD}.count"): {
+                        "$add": [{ "$ifNull": [&count, 0] }, 1]
+                    },
+                    format!("{REFRESH_FAILURE_FIELD}.lastError"): error,
+                    format!("{REFRESH_FAILURE_FIELD}.lastAttemptAt"): now,
+                }
+            },
+            doc! {
+                "$set": {
+                    format!("{REFRESH_FAILURE_FIELD}.nextAttemptAt"): {
+                        "$toLong": {
+                            "$add": [now, {
+                                "$min": [backoff.max_in_seconds(), {
+                                    "$multiply": [backoff.base_in_seconds(), {
+                                        "$pow": [2, { "$subtract": [&count, 1] }]
+                                    }]
+                                }]
+                            }]
+                        }
+                    }
+                }
+            },
+        ];
+
+        self.collection
+            .update_one(doc! { "_id": id.to_string() }, pipeline)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn require_reauthorization(
+        &self,
+        id: &Id,
+        secrets_service_id: &str,
+        error: &str,
+    ) -> Result<(), PicaError> {
+        self.update_one(
+            &id.to_string(),
+            doc! {
+                "$set": {
+                    REAUTHORIZATION_REQUIRED_FIELD: true,
+                    REAUTHORIZATION_SECRET_FIELD: secrets_service_id,
+                    "hasError": true,
+                    "error": error,
+                },
+                "$unset": {
+                    REFRESH_FAILURE_FIELD: "",
+                }
+            },
+        )
+        .await
+    }
+
+    async fn record_validation(&self, id: &Id, error: Option<&str>) -> Result<(), PicaError> {
+        let mut validation = doc! {
+            "valid": error.is_none(),
+            "validatedAt": Utc::now().timestamp(),
+        };
+        if let Some(error) = error {
+            validation.insert("error", error);
+        }
+
+        self.update_one(
+            &id.to_string(),
+            doc! { "$set": { TOKEN_VALIDATION_FIELD: validation } },
+        )
+        .await
+    }
+
+    async fn get_unrevoked(
+        &self,
+        max_attempts: u32,
+        limit: u64,
+    ) -> Result<Vec<Connection>, PicaError> {
+        let filter = doc! {
+            "deleted": true,
+            "oauth.enabled": { "$exists": true },
+            REVOKED_AT_FIELD: { "$exists": false },
+            REVOCATION_UNSUPPORTED_FIELD: { "$exists": false },
+            format!("{REVOCATION_FAILURE_FIELD}.attempts"): { "$not": { "$gte": max_attempts } },
+        };
+
+        self.get_many(Some(filter), None, None, Some(limit), None)
+            .await
+    }
+
+    async fn record_revocation(
+        &self,
+        id: &Id,
+        outcome: Result<RevocationOutcome, &str>,
+    ) -> Result<(), PicaError> {
+        let update = match outcome {
+            Ok(RevocationOutcome::Revoked) => doc! {
+                "$set": { REVOKED_AT_FIELD: Utc::now().timestamp() },
+                "$unset": { REVOCATION_FAILURE_FIELD: "" },
+            },
+            // The tokens stay valid, so the connection must not look revoked.
+            Ok(RevocationOutcome::Unsupported) => doc! {
+                "$set": { REVOCATION_UNSUPPORTED_FIELD: Utc::now().timestamp() },
+                "$unset": { REVOCATION_FAILURE_FIELD: "" },
+            },
+            Err(error) => doc! {
+                "$set": { format!("{REVOCATION_FAILURE_FIELD}.lastError"): error },
+                "$inc": { format!("{REVOCATION_FAILURE_FIELD}.attempts"): 1 },
+            },
+        };
+
+        self.update_one(&id.to_string(), update).await
+    }
+
+    async fn authorize(
+        &self,
+        id: &Id,
+        oauth: &OAuth,
+        secrets_service_id: &str,
+    ) -> Result<Option<Connection>, PicaError> {
+        let oauth = bson::to_bson(oauth).map_err(|e| {
+            InternalError::serialize_error(&format!("Failed to serialize oauth: {e}"), None)
+        })?;
+
+        Ok(self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": id.to_string() },
+                doc! {
+                    "$set": {
+                        "oauth": oauth,
+                        "secretsServiceId": secrets_service_id,
+                        "hasError": false,
+                        LAST_REFRESHED_AT_FIELD: Utc::now().timestamp(),
+                    },
+                    "$unset": {
+                        REFRESH_FAILURE_FIELD: "",
+                        REAUTHORIZATION_REQUIRED_FIELD: "",
+                        REAUTHORIZATION_SECRET_FIELD: "",
+                        "error": "",
+                    }
+                },
+            )
+            .return_document(ReturnDocument::Before)
+            .await?)
+    }
+}
+
+#[derive(Deserialize)]
+struct ExpiryDocument {
+    #[serde(rename = "_id")]
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RevocationDocument {
+    revocation: Option<Revocation>,
+}
+
+#[async_trait]
+pub trait DefinitionStorageExt {
+    /// Returns the expiry, validation and grant settings of the definition, with
+    /// their defaults when it has none.
+    async fn get_settings(&self, id: &Id) -> Result<DefinitionSettings, PicaError>;
+
+    /// Returns the revocation endpoint of the definition, if it has one.
+    async fn get_revocation(&self, id: &Id) -> Result<Option<Revocation>, PicaError>;
+
+    /// Returns the ids of the definitions whose expiry policy refreshes connections
+    /// that have no `expires_at`.
+    async fn get_with_expiry_policy(&self) -> Result<Vec<String>, PicaError>;
+}
+
+#[async_trait]
+impl DefinitionStorageExt for MongoStore<ConnectionOAuthDefinition> {
+    async fn get_settings(&self, id: &Id) -> Result<DefinitionSettings, PicaError> {
+        let settings = self
+            .collection
+            .clone_with_type::<DefinitionSettings>()
+            .find_one(doc! { "_id": id.to_string() })
+            .projection(doc! {
+                EXPIRY_POLICY_FIELD: 1,
+                EXPIRY_SKEW_FIELD: 1,
+                VALIDATION_FIELD: 1,
+                GRANT_FIELD: 1,
+            })
+            .await?;
+
+        Ok(settings.unwrap_or_default())
+    }
+
+    async fn get_revocation(&self, id: &Id) -> Result<Option<Revocation>, PicaError> {
+        let revocation = self
+            .collection
+            .clone_with_type::<RevocationDocument>()
+            .find_one(doc! { "_id": id.to_string() })
+            .projection(doc! { REVOCATION_FIELD: 1 })
+            .await?;
+
+        Ok(revocation.and_then(|revocation| revocation.revocation))
+    }
+
+    async fn get_with_expiry_policy(&self) -> Result<Vec<String>, PicaError> {
+        let policies: Vec<ExpiryDocument> = self
+            .collection
+            .clone_with_type::<ExpiryDocument>()
+            .find(doc! {
+                format!("{EXPIRY_POLICY_FIELD}.type"): { "$in": ["interval", "jwt"] },
+            })
+            .projection(doc! { EXPIRY_POLICY_FIELD: 1 })
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(policies.into_iter().map(|policy| policy.id).collect())
+    }
 }
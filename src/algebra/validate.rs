@@ -0,0 +1,99 @@
+use crate::{
+    algebra::authenticate_client,
+    domain::{RefreshError, Unit, Validation},
+};
+use osentities::{oauth_secret::OAuthSecret, InternalError};
+use reqwest::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    Method,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// The only member of an RFC 7662 introspection response that matters here.
+#[derive(Debug, Deserialize)]
+struct Introspection {
+    #[serde(default)]
+    active: bool,
+}
+
+/// Checks that the access token of `secret` is accepted by the provider.
+pub async fn validate(
+    validation: &Validation,
+    secret: &OAuthSecret,
+    client: &ClientWithMiddleware,
+) -> Result<Unit, RefreshError> {
+    match validation {
+        Validation::Introspection { uri, auth_method } => {
+            let mut form = BTreeMap::from([
+                ("token", secret.access_token.as_str()),
+                ("token_type_hint", "access_token"),
+            ]);
+            let request = authenticate_client(client.post(uri), &mut form, *auth_method, secret);
+
+            let response = request.form(&form).send().await.map_err(|e| {
+                warn!("Failed to execute introspection request: {}", e);
+                InternalError::io_err("Failed to execute introspection request", None)
+            })?;
+
+            let status = response.status();
+            if !status.is_success() {
+                return Err(RefreshError::Validation(format!(
+                    "Introspection responded with status {status}"
+                )));
+            }
+
+            let introspection = response.json::<Introspection>().await.map_err(|e| {
+                RefreshError::Validation(format!("Failed to parse introspection response: {e}"))
+            })?;
+
+            if introspection.active {
+                Ok(())
+            } else {
+                Err(RefreshError::Validation(
+                    "Introspection reports the token as inactive".to_string(),
+                ))
+            }
+        }
+        Validation::Probe {
+            uri,
+            method,
+            headers,
+        } => {
+            let method = Method::from_bytes(method.as_bytes()).map_err(|e| {
+                InternalError::invalid_argument(&format!("Invalid probe method: {e}"), None)
+            })?;
+
+            let mut request = client.request(method, uri);
+            for (key, value) in headers {
+                let key = HeaderName::from_bytes(key.as_bytes()).map_err(|e| {
+                    InternalError::invalid_argument(&format!("Invalid probe header: {e}"), None)
+                })?;
+                let value = HeaderValue::from_str(value).map_err(|e| {
+                    InternalError::invalid_argument(&format!("Invalid probe header: {e}"), None)
+                })?;
+                request = request.header(key, value);
+            }
+
+            let response = request
+                .header(AUTHORIZATION, format!("Bearer {}", secret.access_token))
+                .send()
+                .await
+                .map_err(|e| {
+                    warn!("Failed to execute probe request: {}", e);
+                    InternalError::io_err("Failed to execute probe request", None)
+                })?;
+
+            let status = response.status();
+            if status.is_success() {
+                Ok(())
+            } else {
+                Err(RefreshError::Validation(format!(
+                    "Probe responded with status {status}"
+                )))
+            }
+        }
+    }
+}
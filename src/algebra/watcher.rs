@@ -0,0 +1,123 @@
+use crate::{
+    algebra::{
+        change_streams_unsupported, expires_at, horizon, StorageExt, LAST_REFRESHED_AT_FIELD,
+    },
+    domain::{Backoff, Refresh, Unit},
+};
+use chrono::Utc;
+use futures::StreamExt;
+use osentities::{algebra::MongoStore, error::PicaError as Error, Connection};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Delay before a broken change stream is opened again.
+pub const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Backoff between consecutive failures to open the change stream.
+pub const OPEN_BACKOFF: Backoff = Backoff::new(1, 60);
+
+/// Feeds inserted and updated connections that expire before the next
+/// reconciliation into `sender`. Falls back to polling every `poll_interval` when
+/// the deployment does not support change streams, and retries with backoff when
+/// it fails to open them for any other reason.
+pub async fn watch(
+    msg: Refresh,
+    sender: mpsc::Sender<Connection>,
+    connections_store: Arc<MongoStore<Connection>>,
+    poll_interval: Duration,
+) -> Result<Unit, Error> {
+    let mut resume_token = None;
+    let mut failures = 0;
+
+    loop {
+        let mut changes = match connections_store.watch_oauth(resume_token.clone()).await {
+            Ok(changes) => {
+                failures = 0;
+                changes
+            }
+            Err(e) if change_streams_unsupported(&e) => {
+                warn!(
+                    "Change streams are unsupported, polling every {} seconds instead: {}",
+                    poll_interval.as_secs(),
+                    e
+                );
+                return poll(msg, sender, connections_store, poll_interval).await;
+            }
+            Err(e) => {
+                failures += 1;
+                let delay = OPEN_BACKOFF.delay_in_seconds(failures);
+                warn!(
+                    "Failed to open connection change stream, retrying in {} seconds: {}",
+                    delay, e
+                );
+                // The resume token may be the reason, so start over; reconciliation
+                // picks up whatever changed in between.
+                resume_token = None;
+                tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+                continue;
+            }
+        };
+
+        tracing::info!("Watching connections for oauth changes");
+
+        while let Some(event) = changes.next().await {
+            match event {
+                Ok(event) => {
+                    // Tokens written by this service were just refreshed. Scheduling them
+                    // again would refresh a token that lives shorter than the refresh
+                    // window over and over, so they wait for the next reconciliation.
+                    let written_here = event.update_description.as_ref().is_some_and(|update| {
+                        update.updated_fields.contains_key(LAST_REFRESHED_AT_FIELD)
+                    });
+                    if written_here {
+                        continue;
+                    }
+
+                    let Some(connection) = event.full_document else {
+                        continue;
+                    };
+
+                    if is_near_term(&msg, &connection) && sender.send(connection).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!("Connection change stream failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        resume_token = changes.resume_token();
+        tokio::time::sleep(REOPEN_DELAY).await;
+    }
+}
+
+async fn poll(
+    msg: Refresh,
+    sender: mpsc::Sender<Connection>,
+    connections_store: Arc<MongoStore<Connection>>,
+    poll_interval: Duration,
+) -> Result<Unit, Error> {
+    loop {
+        let now = Utc::now();
+        match connections_store.get_by(&now, &horizon(&msg, &now)).await {
+            Ok(connections) => {
+                for connection in connections {
+                    if sender.send(connection).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to poll connections to refresh: {}", e),
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+fn is_near_term(msg: &Refresh, connection: &Connection) -> bool {
+    expires_at(connection)
+        .is_some_and(|expires_at| expires_at <= horizon(msg, &Utc::now()).timestamp())
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+/// Exponential backoff applied between failed refresh attempts of a connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Backoff {
+    base_in_seconds: i64,
+    max_in_seconds: i64,
+}
+
+impl Backoff {
+    pub const fn new(base_in_seconds: i64, max_in_seconds: i64) -> Self {
+        Self {
+            base_in_seconds,
+            max_in_seconds,
+        }
+    }
+
+    pub fn base_in_seconds(&self) -> i64 {
+        self.base_in_seconds
+    }
+
+    pub fn max_in_seconds(&self) -> i64 {
+        self.max_in_seconds
+    }
+
+    /// Delay before the next attempt after `failures` consecutive failures.
+    pub fn delay_in_seconds(&self, failures: u32) -> i64 {
+        let exponent = failures.saturating_sub(1).min(32);
+        self.base_in_seconds
+            .saturating_mul(1_i64 << exponent)
+            .min(self.max_in_seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_from_the_base_for_each_failure() {
+        let backoff = Backoff::new(60, 3600);
+
+        assert_eq!(backoff.delay_in_seconds(0), 60);
+        assert_eq!(backoff.delay_in_seconds(1), 60);
+        assert_eq!(backoff.delay_in_seconds(2), 120);
+        assert_eq!(backoff.delay_in_seconds(3), 240);
+    }
+
+    #[test]
+    fn delay_is_capped_at_the_maximum() {
+        let backoff = Backoff::new(60, 3600);
+
+        assert_eq!(backoff.delay_in_seconds(7), 3600);
+        assert_eq!(backoff.delay_in_seconds(u32::MAX), 3600);
+    }
+}
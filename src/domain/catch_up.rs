@@ -0,0 +1,23 @@
+/// Bounds a sweep over connections whose tokens expired without being refreshed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CatchUp {
+    max_age_in_minutes: i64,
+    limit: u64,
+}
+
+impl CatchUp {
+    pub fn new(max_age_in_minutes: i64, limit: u64) -> Self {
+        Self {
+            max_age_in_minutes,
+            limit,
+        }
+    }
+
+    pub fn max_age_in_minutes(&self) -> i64 {
+        self.max_age_in_minutes
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// How the client authenticates to the token, revocation and introspection
+/// endpoints, named as in RFC 7591.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMethod {
+    /// Client id and secret in an HTTP Basic `Authorization` header.
+    #[default]
+    ClientSecretBasic,
+    /// Client id and secret in the form body.
+    ClientSecretPost,
+    /// Client id in the form body only, for public clients.
+    None,
+}
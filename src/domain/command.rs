@@ -0,0 +1,145 @@
+use osentities::{Id, InternalError, PicaError};
+
+pub const USAGE: &str = "\
+Usage: oauth-refresh [COMMAND]
+
+Commands:
+  run                                  Refresh connections until stopped (default)
+  once [--dry-run]                     Run a single refresh cycle, exiting non-zero on failures
+  refresh <connection-id> [--dry-run]  Refresh one connection regardless of its expiry
+  due [--json]                         List the connections the next cycle would refresh
+  revoke <connection-id>               Revoke the tokens of a deleted connection at its provider
+  help                                 Print this message
+
+With --dry-run the refresh requests are printed with credentials masked instead
+of being sent, and nothing is written. DRY_RUN=true makes `run` a single
+dry-run cycle, like `once --dry-run`.";
+
+/// Subcommand of the `oauth-refresh` binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Once { dry_run: bool },
+    Refresh { id: Id, dry_run: bool },
+    Due { json: bool },
+    Revoke { id: Id },
+    Help,
+}
+
+impl Command {
+    /// Parses the arguments following the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, PicaError> {
+        let mut args = args.into_iter();
+        let command = args.next();
+
+        let command = match command.as_deref() {
+            None | Some("run") => Command::Run,
+            Some("once") => Command::Once {
+                dry_run: flag(&mut args, "--dry-run")?,
+            },
+            Some("refresh") => {
+                let id = args.next().ok_or(InternalError::invalid_argument(
+                    "Missing connection id",
+                    None,
+                ))?;
+                Command::Refresh {
+                    id: id.parse()?,
+                    dry_run: flag(&mut args, "--dry-run")?,
+                }
+            }
+            Some("due") => Command::Due {
+                json: flag(&mut args, "--json")?,
+            },
+            Some("revoke") => {
+                let id = args.next().ok_or(InternalError::invalid_argument(
+                    "Missing connection id",
+                    None,
+                ))?;
+                Command::Revoke { id: id.parse()? }
+            }
+            Some("help" | "-h" | "--help") => Command::Help,
+            Some(command) => {
+                return Err(InternalError::invalid_argument(
+                    &format!("Unknown command: {command}"),
+                    None,
+                ))
+            }
+        };
+
+        match args.next() {
+            Some(arg) => Err(InternalError::invalid_argument(
+                &format!("Unexpected argument: {arg}"),
+                None,
+            )),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Consumes the next argument when it is `name`.
+fn flag<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<bool, PicaError> {
+    match args.next() {
+        None => Ok(false),
+        Some(arg) if arg == name => Ok(true),
+        Some(arg) => Err(InternalError::invalid_argument(
+            &format!("Unknown argument: {arg}"),
+            None,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osentities::id::prefix::IdPrefix;
+
+    fn parse(args: &[&str]) -> Result<Command, PicaError> {
+        Command::parse(args.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn parses_run_by_default() {
+        assert_eq!(parse(&[]).ok(), Some(Command::Run));
+        assert_eq!(parse(&["run"]).ok(), Some(Command::Run));
+    }
+
+    #[test]
+    fn parses_flags() {
+        assert_eq!(
+            parse(&["once", "--dry-run"]).ok(),
+            Some(Command::Once { dry_run: true })
+        );
+        assert_eq!(
+            parse(&["once"]).ok(),
+            Some(Command::Once { dry_run: false })
+        );
+        assert_eq!(
+            parse(&["due", "--json"]).ok(),
+            Some(Command::Due { json: true })
+        );
+        assert_eq!(parse(&["--help"]).ok(), Some(Command::Help));
+    }
+
+    #[test]
+    fn parses_connection_ids() {
+        let id = Id::now(IdPrefix::Connection);
+
+        assert_eq!(
+            parse(&["refresh", &id.to_string(), "--dry-run"]).ok(),
+            Some(Command::Refresh { id, dry_run: true })
+        );
+        assert_eq!(
+            parse(&["revoke", &id.to_string()]).ok(),
+            Some(Command::Revoke { id })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse(&["refresh"]).is_err());
+        assert!(parse(&["refresh", "not-an-id"]).is_err());
+        assert!(parse(&["once", "--json"]).is_err());
+        assert!(parse(&["due", "--json", "extra"]).is_err());
+        assert!(parse(&["unknown"]).is_err());
+    }
+}
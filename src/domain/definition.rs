@@ -0,0 +1,15 @@
+use crate::domain::{ExpirySettings, Grant, Validation};
+use serde::{Deserialize, Serialize};
+
+/// Members of a connection oauth definition read by this service on top of the
+/// ones osentities knows about.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DefinitionSettings {
+    #[serde(flatten)]
+    pub expiry: ExpirySettings,
+    #[serde(default)]
+    pub validation: Option<Validation>,
+    #[serde(default)]
+    pub grant: Grant,
+}
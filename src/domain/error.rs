@@ -0,0 +1,272 @@
+use crate::domain::redact_body;
+use osentities::PicaError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+/// Error codes from RFC 6749 section 5.2 after which retrying the same refresh
+/// token cannot succeed and the end user has to authorize the connection again.
+const TERMINAL_ERRORS: [&str; 1] = ["invalid_grant"];
+
+/// Error codes from RFC 6749 section 5.2 caused by the client or the definition
+/// rather than the grant. They affect every connection of a platform, so they are
+/// retried until the configuration is fixed instead of asking each end user to
+/// authorize again.
+const CONFIGURATION_ERRORS: [&str; 4] = [
+    "invalid_client",
+    "unauthorized_client",
+    "unsupported_grant_type",
+    "invalid_scope",
+];
+
+/// Headers of a token endpoint response that must never end up in logs or errors.
+const SENSITIVE_HEADERS: [&str; 3] = ["set-cookie", "authorization", "www-authenticate"];
+
+const MAX_BODY_LENGTH: usize = 1024;
+
+/// Error response of a token endpoint as described in RFC 6749 section 5.2.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OAuthError {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_uri: Option<String>,
+}
+
+impl OAuthError {
+    /// Returns the error when `json` carries a string `error` member, which is how
+    /// providers signal a rejected token request.
+    pub fn from_response(json: &Value) -> Option<Self> {
+        json.get("error")
+            .and_then(Value::as_str)
+            .and_then(|_| serde_json::from_value(json.clone()).ok())
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        TERMINAL_ERRORS.contains(&self.error.as_str())
+    }
+
+    pub fn is_configuration_error(&self) -> bool {
+        CONFIGURATION_ERRORS.contains(&self.error.as_str())
+    }
+}
+
+impl Display for OAuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{}: {}", self.error, description),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RefreshError {
+    /// The provider rejected the refresh with an RFC 6749 error response.
+    OAuth(OAuthError),
+    /// The provider answered with a non-2xx status that is not an RFC 6749 error.
+    Status {
+        status: u16,
+        headers: BTreeMap<String, String>,
+        body: String,
+    },
+    /// The connection was reauthorized while the refresh was in flight, so the
+    /// refreshed credentials were discarded.
+    Superseded,
+    /// The refreshed access token was stored but failed the validation of its
+    /// definition.
+    Validation(String),
+    Internal(PicaError),
+}
+
+impl RefreshError {
+    /// Terminal errors move the connection into the reauthorization required state
+    /// instead of being retried with backoff.
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            RefreshError::OAuth(e) => e.is_terminal(),
+            RefreshError::Status { .. }
+            | RefreshError::Superseded
+            | RefreshError::Validation(_)
+            | RefreshError::Internal(_) => false,
+        }
+    }
+
+    /// Errors that point at the oauth client or definition of the platform, which
+    /// an operator has to fix.
+    pub fn is_configuration_error(&self) -> bool {
+        match self {
+            RefreshError::OAuth(e) => e.is_configuration_error(),
+            RefreshError::Status { .. }
+            | RefreshError::Superseded
+            | RefreshError::Validation(_)
+            | RefreshError::Internal(_) => false,
+        }
+    }
+
+    /// Builds a status error with sensitive headers dropped and token members of
+    /// the body masked.
+    pub fn status<'a>(
+        status: u16,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        body: &str,
+    ) -> Self {
+        let headers = headers
+            .into_iter()
+            .filter(|(key, _)| !SENSITIVE_HEADERS.contains(&key.to_lowercase().as_str()))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        RefreshError::Status {
+            status,
+            headers,
+            body: redact(body),
+        }
+    }
+}
+
+/// Masks credentials anywhere in a JSON or form encoded body and truncates it.
+fn redact(body: &str) -> String {
+    let body = redact_body(body.as_bytes());
+
+    match body.char_indices().nth(MAX_BODY_LENGTH) {
+        Some((index, _)) => format!("{}...", &body[..index]),
+        None => body,
+    }
+}
+
+impl Display for RefreshError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::OAuth(e) => write!(f, "Provider rejected token request: {}", e),
+            RefreshError::Status { status, body, .. } => {
+                write!(f, "Provider responded with status {}: {}", status, body)
+            }
+            RefreshError::Superseded => {
+                write!(
+                    f,
+                    "Connection was reauthorized while the refresh was in flight"
+                )
+            }
+            RefreshError::Validation(e) => write!(f, "Refreshed token failed validation: {}", e),
+            RefreshError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<PicaError> for RefreshError {
+    fn from(e: PicaError) -> Self {
+        RefreshError::Internal(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_response_reads_rfc_6749_errors() {
+        let error = OAuthError::from_response(&json!({
+            "error": "invalid_grant",
+            "error_description": "Refresh token expired",
+        }))
+        .expect("error response");
+
+        assert_eq!(error.error, "invalid_grant");
+        assert_eq!(
+            error.error_description.as_deref(),
+            Some("Refresh token expired")
+        );
+        assert_eq!(error.error_uri, None);
+    }
+
+    #[test]
+    fn from_response_ignores_bodies_without_a_string_error() {
+        assert_eq!(
+            OAuthError::from_response(&json!({ "access_token": "token" })),
+            None
+        );
+        assert_eq!(
+            OAuthError::from_response(&json!({ "error": { "code": 400 } })),
+            None
+        );
+    }
+
+    #[test]
+    fn only_invalid_grant_is_terminal() {
+        let error = |code: &str| OAuthError {
+            error: code.to_string(),
+            error_description: None,
+            error_uri: None,
+        };
+
+        assert!(error("invalid_grant").is_terminal());
+        for code in CONFIGURATION_ERRORS {
+            assert!(!error(code).is_terminal());
+            assert!(error(code).is_configuration_error());
+        }
+        assert!(!error("temporarily_unavailable").is_terminal());
+        assert!(!error("temporarily_unavailable").is_configuration_error());
+    }
+
+    #[test]
+    fn provider_failures_other_than_rejections_are_not_terminal() {
+        assert!(RefreshError::OAuth(OAuthError {
+            error: "invalid_grant".to_string(),
+            error_description: None,
+            error_uri: None,
+        })
+        .is_terminal());
+        assert!(!RefreshError::Superseded.is_terminal());
+        assert!(!RefreshError::status(503, [], "").is_terminal());
+    }
+
+    #[test]
+    fn redact_masks_nested_json_credentials() {
+        let body = json!({
+            "error": "server_error",
+            "data": { "refresh_token": "secret", "tokens": [{ "access_token": "secret" }] },
+        })
+        .to_string();
+
+        let redacted = redact(&body);
+
+        assert!(!redacted.contains("secret"));
+        assert!(redacted.contains("server_error"));
+    }
+
+    #[test]
+    fn redact_masks_form_encoded_credentials() {
+        assert_eq!(
+            redact("grant_type=refresh_token&refresh_token=secret&client_secret=secret"),
+            "grant_type=refresh_token&refresh_token=[REDACTED]&client_secret=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn redact_truncates_long_bodies() {
+        let redacted = redact(&"a".repeat(MAX_BODY_LENGTH + 10));
+
+        assert_eq!(redacted.len(), MAX_BODY_LENGTH + 3);
+        assert!(redacted.ends_with("..."));
+    }
+
+    #[test]
+    fn status_drops_sensitive_headers() {
+        let RefreshError::Status { headers, .. } = RefreshError::status(
+            502,
+            [("Set-Cookie", "session"), ("Content-Type", "text/plain")],
+            "",
+        ) else {
+            panic!("status error");
+        };
+
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("Content-Type"));
+    }
+}
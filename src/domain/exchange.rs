@@ -0,0 +1,72 @@
+use osentities::{
+    environment::Environment, ownership::Ownership, ConnectionIdentityType, Id, Throughput,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{Debug, Formatter};
+
+/// Authorization code handed to `redirect_uri` by the provider, exchanged for the
+/// tokens of a connection.
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Exchange {
+    pub code: String,
+    pub redirect_uri: String,
+    pub connection_oauth_definition_id: Id,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Additional values the definition templates may refer to, such as a tenant.
+    #[serde(default)]
+    pub metadata: Value,
+    /// OAuth state of a PKCE authorization request, whose code verifier is sent
+    /// along with the code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    pub target: ExchangeTarget,
+}
+
+impl Debug for Exchange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Exchange")
+            .field("code", &"***")
+            .field("redirect_uri", &self.redirect_uri)
+            .field(
+                "connection_oauth_definition_id",
+                &self.connection_oauth_definition_id,
+            )
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"***")
+            .field("metadata", &self.metadata)
+            .field("state", &self.state)
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+/// Connection that receives the tokens of an exchange.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExchangeTarget {
+    /// Authorizes an existing connection again, replacing its credentials.
+    Existing { connection_id: Id },
+    /// Creates the connection. Everything else is taken from its connection
+    /// definition and the result of the exchange.
+    New { connection: Box<NewConnection> },
+}
+
+/// Fields of a new connection that belong to the caller.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NewConnection {
+    pub connection_definition_id: Id,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub group: String,
+    pub environment: Environment,
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub identity_type: Option<ConnectionIdentityType>,
+    pub ownership: Ownership,
+    pub throughput: Throughput,
+}
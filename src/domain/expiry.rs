@@ -0,0 +1,218 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Members of a token response holding an absolute expiry.
+const ABSOLUTE_EXPIRY_FIELDS: [&str; 3] = ["expires_at", "expires_on", "expiry"];
+/// Members of a token response holding the lifetime of the token in seconds.
+const RELATIVE_EXPIRY_FIELDS: [&str; 2] = ["expires_in", "expires"];
+/// Epoch timestamps above this value are taken to be in milliseconds.
+const MILLISECONDS_THRESHOLD: i64 = 100_000_000_000;
+
+/// How the expiry of a connection is determined. Read from the `expiryPolicy`
+/// member of the connection oauth definition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExpiryPolicy {
+    /// Refresh the connection every `hours` hours when the provider omits
+    /// `expires_in` from its token response.
+    Interval { hours: i64 },
+    /// Read the expiry from the `exp` and `iat` claims of a JWT of the token
+    /// response, which are trusted over the expiry the response reports.
+    #[serde(rename_all = "camelCase")]
+    Jwt {
+        #[serde(default)]
+        source: JwtSource,
+        /// JWKS the signature of the token is verified against. Without it the
+        /// claims are read unverified.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        jwks_uri: Option<String>,
+    },
+    /// Never refresh the connection when the provider omits `expires_in`.
+    #[default]
+    Never,
+}
+
+impl ExpiryPolicy {
+    /// Expiry of a connection whose provider reported none, neither in its token
+    /// response nor in the claims of its token.
+    pub fn expires_at(&self, now: DateTime<Utc>) -> Option<i64> {
+        match self {
+            ExpiryPolicy::Interval { hours } => Some((now + Duration::hours(*hours)).timestamp()),
+            ExpiryPolicy::Jwt { .. } | ExpiryPolicy::Never => None,
+        }
+    }
+}
+
+/// Token of a token response whose JWT claims give the expiry of the connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum JwtSource {
+    #[default]
+    AccessToken,
+    IdToken,
+}
+
+/// Expiry settings of a connection oauth definition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpirySettings {
+    #[serde(default)]
+    pub expiry_policy: ExpiryPolicy,
+    /// Seconds taken off every expiry reported by the provider, to absorb network
+    /// latency and clock drift.
+    #[serde(default)]
+    pub expiry_skew_in_seconds: i64,
+}
+
+/// Reads the expiry of a token response as seconds since the epoch. Absolute
+/// expiries win over lifetimes, which are counted from `sent_at`. Numbers may be
+/// given as strings, absolute expiries in milliseconds or as RFC 3339 dates.
+pub fn normalize_expires_at(json: &Value, sent_at: DateTime<Utc>) -> Option<i64> {
+    ABSOLUTE_EXPIRY_FIELDS
+        .iter()
+        .find_map(|field| json.get(field).and_then(timestamp))
+        .or_else(|| {
+            RELATIVE_EXPIRY_FIELDS
+                .iter()
+                .find_map(|field| json.get(field).and_then(number))
+                .filter(|seconds| *seconds > 0)
+                .map(|seconds| sent_at.timestamp() + seconds)
+        })
+}
+
+fn number(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number
+            .as_i64()
+            .or_else(|| number.as_f64().map(|number| number as i64)),
+        Value::String(string) => {
+            let string = string.trim();
+            string
+                .parse::<i64>()
+                .ok()
+                .or_else(|| string.parse::<f64>().ok().map(|number| number as i64))
+        }
+        _ => None,
+    }
+}
+
+fn timestamp(value: &Value) -> Option<i64> {
+    let timestamp = match number(value) {
+        Some(milliseconds) if milliseconds > MILLISECONDS_THRESHOLD => milliseconds / 1000,
+        Some(seconds) => seconds,
+        None => DateTime::parse_from_rfc3339(value.as_str()?.trim())
+            .ok()?
+            .timestamp(),
+    };
+
+    (timestamp > 0).then_some(timestamp)
+}
+
+/// Expiry a connection is stored with after a token request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionExpiry {
+    /// Lifetime reported by the provider, counted from when the request was sent.
+    pub expires_in: Option<i32>,
+    pub expires_at: Option<i64>,
+    /// Policy that decided `expires_at` when the provider reported no expiry.
+    pub policy: Option<ExpiryPolicy>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sent_at() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).expect("valid timestamp")
+    }
+
+    #[test]
+    fn lifetimes_count_from_when_the_request_was_sent() {
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires_in": 3600 }), sent_at()),
+            Some(1_700_003_600)
+        );
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires": " 3600 " }), sent_at()),
+            Some(1_700_003_600)
+        );
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires_in": 3600.5 }), sent_at()),
+            Some(1_700_003_600)
+        );
+    }
+
+    #[test]
+    fn absolute_expiries_win_over_lifetimes() {
+        assert_eq!(
+            normalize_expires_at(
+                &json!({ "expires_at": 1_800_000_000, "expires_in": 3600 }),
+                sent_at()
+            ),
+            Some(1_800_000_000)
+        );
+    }
+
+    #[test]
+    fn absolute_expiries_may_be_milliseconds_or_dates() {
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires_on": "1800000000000" }), sent_at()),
+            Some(1_800_000_000)
+        );
+        assert_eq!(
+            normalize_expires_at(&json!({ "expiry": "2027-01-15T08:00:00Z" }), sent_at()),
+            Some(1_800_000_000)
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_expiries_are_ignored() {
+        assert_eq!(normalize_expires_at(&json!({}), sent_at()), None);
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires_in": 0 }), sent_at()),
+            None
+        );
+        assert_eq!(
+            normalize_expires_at(&json!({ "expires_at": "soon" }), sent_at()),
+            None
+        );
+    }
+
+    #[test]
+    fn policies_decide_expiries_the_provider_omits() {
+        assert_eq!(
+            ExpiryPolicy::Interval { hours: 2 }.expires_at(sent_at()),
+            Some(1_700_007_200)
+        );
+        assert_eq!(ExpiryPolicy::default().expires_at(sent_at()), None);
+    }
+
+    #[test]
+    fn jwt_policies_default_to_the_unverified_access_token() {
+        let policy: ExpiryPolicy =
+            serde_json::from_value(json!({ "type": "jwt" })).expect("policy deserializes");
+        assert_eq!(
+            policy,
+            ExpiryPolicy::Jwt {
+                source: JwtSource::AccessToken,
+                jwks_uri: None,
+            }
+        );
+
+        let policy: ExpiryPolicy = serde_json::from_value(json!({
+            "type": "jwt",
+            "source": "idToken",
+            "jwksUri": "https://example.com/jwks",
+        }))
+        .expect("policy deserializes");
+        assert_eq!(
+            policy,
+            ExpiryPolicy::Jwt {
+                source: JwtSource::IdToken,
+                jwks_uri: Some("https://example.com/jwks".to_string()),
+            }
+        );
+    }
+}
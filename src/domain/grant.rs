@@ -0,0 +1,23 @@
+use super::ClientAuthMethod;
+use serde::{Deserialize, Serialize};
+
+/// How the tokens of a connection are renewed. Read from the `grant` member of
+/// the connection oauth definition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Grant {
+    /// Exchange the refresh token with the templated refresh request.
+    #[default]
+    RefreshToken,
+    /// Request a new token with the client credentials, for machine to machine
+    /// connections without a refresh token. Sent to the uri of the refresh request.
+    #[serde(rename_all = "camelCase")]
+    ClientCredentials {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scope: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audience: Option<String>,
+        #[serde(default)]
+        auth_method: ClientAuthMethod,
+    },
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Identifies the replica holding a connection and how long the claim lasts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Lease {
+    owner: String,
+    duration_in_seconds: i64,
+}
+
+impl Lease {
+    pub fn new(owner: &str, duration_in_seconds: i64) -> Self {
+        Self {
+            owner: owner.to_string(),
+            duration_in_seconds,
+        }
+    }
+
+    /// Lease of a single refresh attempt. Tasks of the same replica each hold a
+    /// lease of their own, so one cannot release a claim made by another.
+    pub fn attempt(&self) -> Self {
+        Self {
+            owner: format!("{}:{}", self.owner, Uuid::new_v4()),
+            duration_in_seconds: self.duration_in_seconds,
+        }
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn duration_in_seconds(&self) -> i64 {
+        self.duration_in_seconds
+    }
+}
@@ -1,7 +1,43 @@
+mod backoff;
+mod catch_up;
+mod client_auth;
+mod command;
+mod definition;
+mod error;
+mod exchange;
+mod expiry;
+mod grant;
+mod lease;
+mod outbox;
+mod pkce;
 mod refresh;
+mod rendered;
+mod revocation;
+mod schedule;
+mod shutdown;
+mod status;
 mod trigger;
+mod validation;
 
+pub use backoff::*;
+pub use catch_up::*;
+pub use client_auth::*;
+pub use command::*;
+pub use definition::*;
+pub use error::*;
+pub use exchange::*;
+pub use expiry::*;
+pub use grant::*;
+pub use lease::*;
+pub use outbox::*;
+pub use pkce::*;
 pub use refresh::*;
+pub use rendered::*;
+pub use revocation::*;
+pub use schedule::*;
+pub use shutdown::*;
+pub use status::*;
 pub use trigger::*;
+pub use validation::*;
 
 pub type Unit = ();
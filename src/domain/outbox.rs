@@ -0,0 +1,30 @@
+use osentities::{environment::Environment, Id, OAuth};
+use serde::{Deserialize, Serialize};
+
+/// Refreshed credentials that still have to be written to the secrets service
+/// and linked to their connection. Entries are stored before the secrets service
+/// is called so a failed write never loses a rotated refresh token.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub connection_id: Id,
+    pub buildable_id: String,
+    pub environment: Environment,
+    /// `secretsServiceId` of the connection when the refresh started.
+    pub secrets_service_id: String,
+    /// Encrypted `OAuthSecret` returned by the provider.
+    pub secret: String,
+    pub oauth: OAuth,
+    /// Whether completing the entry clears the refresh failure of the connection.
+    pub clear_failure: bool,
+    /// Id of the secret once it has been created, so a retry does not create it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_secret_id: Option<String>,
+    pub attempts: u32,
+    pub created_at: i64,
+    pub next_attempt_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
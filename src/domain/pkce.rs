@@ -0,0 +1,125 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bytes of entropy in a code verifier, which encode to the 43 characters
+/// RFC 7636 requires at least.
+const VERIFIER_BYTES: usize = 32;
+
+/// Transformation of the code verifier into the code challenge (RFC 7636 section 4.2).
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PkceMethod {
+    #[default]
+    S256,
+    #[serde(rename = "plain")]
+    Plain,
+}
+
+impl PkceMethod {
+    pub fn challenge(&self, code_verifier: &str) -> String {
+        match self {
+            PkceMethod::S256 => URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())),
+            PkceMethod::Plain => code_verifier.to_string(),
+        }
+    }
+}
+
+/// Code verifier of an authorization request and the challenge sent along with it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pkce {
+    pub code_verifier: String,
+    pub code_challenge: String,
+    pub code_challenge_method: PkceMethod,
+}
+
+impl Pkce {
+    pub fn generate(method: PkceMethod) -> Self {
+        let mut bytes = [0u8; VERIFIER_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let code_verifier = URL_SAFE_NO_PAD.encode(bytes);
+
+        Self {
+            code_challenge: method.challenge(&code_verifier),
+            code_verifier,
+            code_challenge_method: method,
+        }
+    }
+}
+
+impl std::fmt::Debug for Pkce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkce")
+            .field("code_verifier", &"***")
+            .field("code_challenge", &self.code_challenge)
+            .field("code_challenge_method", &self.code_challenge_method)
+            .finish()
+    }
+}
+
+/// What the authorization request needs: the challenge, and the state that later
+/// identifies the verifier when the code is exchanged.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PkceChallenge {
+    pub state: String,
+    pub code_challenge: String,
+    pub code_challenge_method: PkceMethod,
+    pub expires_at: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s256_challenge_matches_rfc_7636_appendix_b() {
+        assert_eq!(
+            PkceMethod::S256.challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn plain_challenge_is_the_verifier() {
+        assert_eq!(PkceMethod::Plain.challenge("verifier"), "verifier");
+    }
+
+    #[test]
+    fn generated_verifiers_are_valid_and_unique() {
+        let pkce = Pkce::generate(PkceMethod::S256);
+
+        assert_eq!(pkce.code_verifier.len(), 43);
+        assert!(pkce
+            .code_verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(
+            pkce.code_challenge,
+            PkceMethod::S256.challenge(&pkce.code_verifier)
+        );
+        assert_ne!(
+            pkce.code_verifier,
+            Pkce::generate(PkceMethod::S256).code_verifier
+        );
+    }
+
+    #[test]
+    fn debug_hides_the_verifier() {
+        let pkce = Pkce::generate(PkceMethod::S256);
+
+        assert!(!format!("{pkce:?}").contains(&pkce.code_verifier));
+    }
+
+    #[test]
+    fn methods_serialize_as_in_rfc_7636() {
+        assert_eq!(
+            serde_json::to_value(PkceMethod::S256).ok(),
+            Some(serde_json::json!("S256"))
+        );
+        assert_eq!(
+            serde_json::to_value(PkceMethod::Plain).ok(),
+            Some(serde_json::json!("plain"))
+        );
+    }
+}
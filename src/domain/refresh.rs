@@ -1,21 +1,126 @@
+use super::{Backoff, CatchUp, Lease, Shutdown};
 use serde::Serialize;
 use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
 #[derive(Debug, Clone)]
 pub struct Refresh {
     refresh_before_in_minutes: i64,
+    reconcile_interval_in_seconds: u64,
+    concurrency: usize,
+    permits: Arc<Semaphore>,
+    lease: Lease,
+    backoff: Backoff,
+    catch_up: Option<CatchUp>,
+    shutdown: Shutdown,
+    dry_run: bool,
+    heartbeat: bool,
 }
 
 impl Refresh {
-    pub fn new(refresh_before_in_minutes: i64) -> Self {
+    pub fn new(
+        refresh_before_in_minutes: i64,
+        reconcile_interval_in_seconds: u64,
+        concurrency: usize,
+        permits: Arc<Semaphore>,
+        lease: Lease,
+        backoff: Backoff,
+    ) -> Self {
         Self {
             refresh_before_in_minutes,
+            reconcile_interval_in_seconds,
+            concurrency,
+            permits,
+            lease,
+            backoff,
+            catch_up: None,
+            shutdown: Shutdown::default(),
+            dry_run: false,
+            heartbeat: false,
         }
     }
 
+    /// Renders the refresh requests instead of sending them. Nothing is leased,
+    /// written to the secrets service or stored on the connections.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Stops starting new refreshes once `shutdown` fires.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Turns the refresh into a sweep over already expired connections.
+    pub fn with_catch_up(mut self, catch_up: CatchUp) -> Self {
+        self.catch_up = Some(catch_up);
+        self
+    }
+
+    /// Records a liveness heartbeat for every finished refresh. Only the scheduler
+    /// sets it, as liveness tracks the scheduler alone.
+    pub fn with_heartbeat(mut self) -> Self {
+        self.heartbeat = true;
+        self
+    }
+
     pub fn refresh_before_in_minutes(&self) -> i64 {
         self.refresh_before_in_minutes
     }
+
+    pub fn reconcile_interval_in_seconds(&self) -> u64 {
+        self.reconcile_interval_in_seconds
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Permits shared by every refresh of the process, bounding how many run at
+    /// once across all callers rather than per call.
+    pub fn permits(&self) -> &Arc<Semaphore> {
+        &self.permits
+    }
+
+    pub fn lease(&self) -> &Lease {
+        &self.lease
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    pub fn catch_up(&self) -> Option<CatchUp> {
+        self.catch_up
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn heartbeat(&self) -> bool {
+        self.heartbeat
+    }
+}
+
+/// Outcome counts of a refresh cycle.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshSummary {
+    pub refreshed: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub superseded: u64,
+    /// Requests rendered by a dry run, one per connection.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rendered: Vec<Refreshed>,
 }
 
 #[derive(Debug, Clone, Serialize)]
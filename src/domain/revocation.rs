@@ -0,0 +1,34 @@
+use crate::domain::ClientAuthMethod;
+use serde::{Deserialize, Serialize};
+
+/// Token revoked at the provider (RFC 7009 section 2.1).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenTypeHint {
+    /// Revoking the refresh token also invalidates the access tokens issued with
+    /// it at providers that support it.
+    #[default]
+    RefreshToken,
+    AccessToken,
+}
+
+/// Token revocation endpoint of a provider. Read from the `revocation` member of
+/// the connection oauth definition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Revocation {
+    pub uri: String,
+    #[serde(default)]
+    pub auth_method: ClientAuthMethod,
+    #[serde(default)]
+    pub token_type_hint: TokenTypeHint,
+}
+
+/// Result of revoking the tokens of a connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RevocationOutcome {
+    Revoked,
+    /// The definition has no revocation endpoint.
+    Unsupported,
+}
@@ -0,0 +1,168 @@
+use super::ExpiryPolicy;
+use osentities::{Connection, Id};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+/// Unix timestamp at which a connection expiring at `expires_at` is due for
+/// refresh, `refresh_before_in_minutes` ahead of its expiry. Connections without
+/// an expiry are due at `now` when the expiry policy of their definition refreshes
+/// them, and never otherwise.
+pub fn due_at(
+    expires_at: Option<i64>,
+    refresh_before_in_minutes: i64,
+    policy: &ExpiryPolicy,
+    now: i64,
+) -> Option<i64> {
+    match expires_at {
+        Some(expires_at) => Some(expires_at - refresh_before_in_minutes * 60),
+        None => (*policy != ExpiryPolicy::Never).then_some(now),
+    }
+}
+
+/// Time-ordered queue of connections keyed by the unix timestamp at which they
+/// are due for refresh. Scheduling a connection again replaces its earlier entry.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    queue: BinaryHeap<Reverse<(i64, Id)>>,
+    entries: HashMap<Id, (i64, Connection)>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, due_at: i64, connection: Connection) {
+        self.queue.push(Reverse((due_at, connection.id)));
+        self.entries.insert(connection.id, (due_at, connection));
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the timestamp of the earliest entry, discarding entries that were
+    /// replaced by a later `insert`.
+    pub fn next_due(&mut self) -> Option<i64> {
+        while let Some(Reverse((due_at, id))) = self.queue.peek() {
+            match self.entries.get(id) {
+                Some((current, _)) if current == due_at => return Some(*due_at),
+                _ => {
+                    self.queue.pop();
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Removes and returns every connection due at or before `now`.
+    pub fn pop_due(&mut self, now: i64) -> Vec<Connection> {
+        let mut due = vec![];
+        while let Some(due_at) = self.next_due() {
+            if due_at > now {
+                break;
+            }
+
+            if let Some(Reverse((_, id))) = self.queue.pop() {
+                if let Some((_, connection)) = self.entries.remove(&id) {
+                    due.push(connection);
+                }
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osentities::{
+        environment::Environment, id::prefix::IdPrefix, ownership::Ownership,
+        record_metadata::RecordMetadata, settings::Settings, ConnectionType, Throughput,
+    };
+
+    fn connection() -> Connection {
+        Connection {
+            id: Id::now(IdPrefix::Connection),
+            platform_version: "1.0.0".to_string(),
+            connection_definition_id: Id::now(IdPrefix::ConnectionDefinition),
+            r#type: ConnectionType::Api {},
+            key: "test::platform::group".into(),
+            group: "group".to_string(),
+            name: None,
+            environment: Environment::Test,
+            platform: "platform".into(),
+            secrets_service_id: "secret".to_string(),
+            event_access_id: None,
+            access_key: None,
+            identity: None,
+            identity_type: None,
+            settings: Settings::default(),
+            throughput: Throughput {
+                key: "throughput".to_string(),
+                limit: 100,
+            },
+            ownership: Ownership::default(),
+            oauth: None,
+            has_error: false,
+            error: None,
+            record_metadata: RecordMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn due_at_is_ahead_of_the_expiry() {
+        assert_eq!(due_at(Some(1_000), 10, &ExpiryPolicy::Never, 0), Some(400));
+    }
+
+    #[test]
+    fn due_at_without_expiry_follows_the_policy() {
+        assert_eq!(due_at(None, 10, &ExpiryPolicy::Never, 50), None);
+        assert_eq!(
+            due_at(None, 10, &ExpiryPolicy::Interval { hours: 1 }, 50),
+            Some(50)
+        );
+    }
+
+    #[test]
+    fn pop_due_returns_due_connections_in_order() {
+        let (first, second, later) = (connection(), connection(), connection());
+        let mut schedule = Schedule::new();
+        schedule.insert(20, second.clone());
+        schedule.insert(30, later.clone());
+        schedule.insert(10, first.clone());
+
+        let due: Vec<Id> = schedule.pop_due(20).into_iter().map(|c| c.id).collect();
+
+        assert_eq!(due, vec![first.id, second.id]);
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule.next_due(), Some(30));
+    }
+
+    #[test]
+    fn insert_replaces_the_earlier_entry() {
+        let connection = connection();
+        let mut schedule = Schedule::new();
+        schedule.insert(10, connection.clone());
+        schedule.insert(40, connection.clone());
+
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule.next_due(), Some(40));
+        assert!(schedule.pop_due(10).is_empty());
+        assert_eq!(schedule.pop_due(40).len(), 1);
+        assert!(schedule.is_empty());
+    }
+}
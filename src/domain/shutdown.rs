@@ -0,0 +1,72 @@
+use tokio::sync::watch;
+
+/// Signals that the process is shutting down and no new refreshes should start.
+/// The default value never fires.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    receiver: Option<watch::Receiver<bool>>,
+}
+
+impl Shutdown {
+    pub fn new(receiver: watch::Receiver<bool>) -> Self {
+        Self {
+            receiver: Some(receiver),
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.receiver
+            .as_ref()
+            .is_some_and(|receiver| *receiver.borrow())
+    }
+
+    /// Resolves once shutdown has been triggered. Pends forever when the sender
+    /// is gone without having triggered it.
+    pub async fn triggered(&self) {
+        let Some(mut receiver) = self.receiver.clone() else {
+            return std::future::pending().await;
+        };
+
+        if receiver.wait_for(|triggered| *triggered).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn default_never_fires() {
+        assert!(!Shutdown::default().is_triggered());
+    }
+
+    #[tokio::test]
+    async fn fires_once_the_sender_triggers_it() {
+        let (sender, receiver) = watch::channel(false);
+        let shutdown = Shutdown::new(receiver);
+        assert!(!shutdown.is_triggered());
+
+        sender.send(true).expect("receiver alive");
+
+        assert!(shutdown.is_triggered());
+        tokio::time::timeout(Duration::from_secs(1), shutdown.triggered())
+            .await
+            .expect("shutdown triggered");
+    }
+
+    #[tokio::test]
+    async fn pends_when_the_sender_is_dropped_untriggered() {
+        let (sender, receiver) = watch::channel(false);
+        let shutdown = Shutdown::new(receiver);
+        drop(sender);
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), shutdown.triggered())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use super::{due_at, ExpiryPolicy, TokenValidation};
+use chrono::Utc;
+use osentities::{Connection, OAuth};
+use serde::{Deserialize, Serialize};
+
+/// Refresh bookkeeping stored on a connection document.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshState {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(default)]
+    pub oauth: Option<OAuth>,
+    #[serde(default)]
+    pub refresh_failure: Option<RefreshFailure>,
+    #[serde(default)]
+    pub refresh_lease: Option<RefreshLease>,
+    #[serde(default)]
+    pub reauthorization_required: bool,
+    /// Secret the reauthorization flag was raised for. Absent on flags raised
+    /// before it was recorded, which hold for any secret.
+    #[serde(default)]
+    pub reauthorization_secrets_service_id: Option<String>,
+    #[serde(default)]
+    pub secrets_service_id: Option<String>,
+    #[serde(default)]
+    pub last_refreshed_at: Option<i64>,
+    #[serde(default)]
+    pub token_validation: Option<TokenValidation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshFailure {
+    pub count: i64,
+    pub last_error: String,
+    pub last_attempt_at: i64,
+    pub next_attempt_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshLease {
+    pub owner: String,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RefreshOutcome {
+    Refreshed,
+    Failed,
+    ReauthorizationRequired,
+    Unknown,
+}
+
+impl RefreshState {
+    /// Whether the connection waits for the end user to authorize it again. The
+    /// flag is stale once the connection's secret was replaced since.
+    pub fn reauthorization_required(&self) -> bool {
+        self.reauthorization_required
+            && match &self.reauthorization_secrets_service_id {
+                Some(failed) => self.secrets_service_id.as_ref() == Some(failed),
+                None => true,
+            }
+    }
+}
+
+/// Last refresh outcome of a connection and when it is refreshed next.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshStatus {
+    pub id: String,
+    pub outcome: RefreshOutcome,
+    pub last_refreshed_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    /// `None` when the connection is not refreshed again without reauthorization
+    /// or has no expiry to schedule by.
+    pub next_refresh_at: Option<i64>,
+    pub failure: Option<RefreshFailure>,
+    pub lease: Option<RefreshLease>,
+    pub validation: Option<TokenValidation>,
+}
+
+impl RefreshStatus {
+    /// `policy` is the expiry policy of the connection's definition, which decides
+    /// whether a connection without an expiry is refreshed at all.
+    pub fn new(state: RefreshState, refresh_before_in_minutes: i64, policy: ExpiryPolicy) -> Self {
+        let expires_at = match &state.oauth {
+            Some(OAuth::Enabled { expires_at, .. }) => *expires_at,
+            _ => None,
+        };
+
+        let reauthorization_required = state.reauthorization_required();
+        let outcome = if reauthorization_required {
+            RefreshOutcome::ReauthorizationRequired
+        } else if state.refresh_failure.is_some() {
+            RefreshOutcome::Failed
+        } else if state.last_refreshed_at.is_some() {
+            RefreshOutcome::Refreshed
+        } else {
+            RefreshOutcome::Unknown
+        };
+
+        let next_refresh_at = if reauthorization_required {
+            None
+        } else {
+            let due_at = due_at(
+                expires_at,
+                refresh_before_in_minutes,
+                &policy,
+                Utc::now().timestamp(),
+            );
+            let retry_at = state
+                .refresh_failure
+                .as_ref()
+                .map(|failure| failure.next_attempt_at);
+            match (due_at, retry_at) {
+                (Some(due_at), Some(retry_at)) => Some(due_at.max(retry_at)),
+                (due_at, retry_at) => due_at.or(retry_at),
+            }
+        };
+
+        Self {
+            id: state.id,
+            outcome,
+            last_refreshed_at: state.last_refreshed_at,
+            expires_at,
+            next_refresh_at,
+            failure: state.refresh_failure,
+            lease: state.refresh_lease,
+            validation: state.token_validation,
+        }
+    }
+}
+
+/// Connection listed as due for refresh.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DueConnection {
+    pub id: String,
+    pub platform: String,
+    pub environment: String,
+    pub expires_at: Option<i64>,
+}
+
+impl From<&Connection> for DueConnection {
+    fn from(connection: &Connection) -> Self {
+        Self {
+            id: connection.id.to_string(),
+            platform: connection.platform.to_string(),
+            environment: connection.environment.to_string(),
+            expires_at: match &connection.oauth {
+                Some(OAuth::Enabled { expires_at, .. }) => *expires_at,
+                _ => None,
+            },
+        }
+    }
+}
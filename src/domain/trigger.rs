@@ -3,11 +3,26 @@ use osentities::Connection;
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Trigger {
     connection: Connection,
+    dry_run: bool,
 }
 
 impl Trigger {
     pub fn new(connection: Connection) -> Self {
-        Self { connection }
+        Self {
+            connection,
+            dry_run: false,
+        }
+    }
+
+    /// Renders the refresh request instead of sending it, leaving the provider,
+    /// the secrets service and the connection untouched.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
     }
 
     pub fn connection(&self) -> &Connection {
@@ -0,0 +1,42 @@
+use super::ClientAuthMethod;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Check that a refreshed access token is accepted by the provider. Read from the
+/// `validation` member of the connection oauth definition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Validation {
+    /// RFC 7662 introspection, which passes when the token is `active`.
+    #[serde(rename_all = "camelCase")]
+    Introspection {
+        uri: String,
+        #[serde(default)]
+        auth_method: ClientAuthMethod,
+    },
+    /// Request made with the token as a bearer token, typically to a "whoami"
+    /// endpoint, which passes on any successful status.
+    #[serde(rename_all = "camelCase")]
+    Probe {
+        uri: String,
+        #[serde(default = "default_method")]
+        method: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+/// Outcome of the last validation of a connection, stored on the connection
+/// document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenValidation {
+    pub valid: bool,
+    pub validated_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
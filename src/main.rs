@@ -1,15 +1,51 @@
+use chrono::{DateTime, Utc};
 use dotenvy::dotenv;
 use envconfig::Envconfig;
-use oauth_refresh::{refresh, AppState, Refresh, RefreshConfig};
-use osentities::telemetry::{get_subscriber, init_subscriber};
-use std::time::Duration;
+use oauth_refresh::{
+    admin_router, drain_outbox, expires_at, find_connections, health_router, refresh,
+    refresh_connections, revoke_connection, schedule, serve, sweep_revocations, watch, AppState,
+    Backoff, CatchUp, Command, DueConnection, HealthThresholds, Lease, Refresh, RefreshConfig,
+    Shutdown, StorageExt, USAGE,
+};
+use osentities::{
+    telemetry::{get_subscriber, init_subscriber},
+    Id,
+};
+use std::{process::ExitCode, time::Duration};
+use tokio::sync::mpsc;
+
+const CHANGES_BUFFER: usize = 1024;
+/// Exit code when in-flight refreshes did not finish before the shutdown deadline.
+const DEADLINE_EXCEEDED: u8 = 2;
+/// Exit code for unknown commands or arguments.
+const USAGE_ERROR: u8 = 64;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     dotenv().ok();
 
-    let suscriber = get_subscriber("oauth-refresh".into(), "info".into(), std::io::stdout, None);
-    init_subscriber(suscriber);
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(Command::Help) => {
+            println!("{USAGE}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Ok(ExitCode::from(USAGE_ERROR));
+        }
+    };
+
+    // Commands other than `run` print their results to stdout, so logs go to stderr.
+    if command == Command::Run {
+        let suscriber =
+            get_subscriber("oauth-refresh".into(), "info".into(), std::io::stdout, None);
+        init_subscriber(suscriber);
+    } else {
+        let suscriber =
+            get_subscriber("oauth-refresh".into(), "info".into(), std::io::stderr, None);
+        init_subscriber(suscriber);
+    }
 
     let configuration = RefreshConfig::init_from_env()?;
 
@@ -19,26 +55,366 @@ async fn main() -> anyhow::Result<()> {
         &configuration,
         "\n"
     );
+    if configuration.sleep_timer().is_some() {
+        tracing::warn!(
+            "SLEEP_TIMER_IN_SECONDS is deprecated, set RECONCILE_INTERVAL_IN_SECONDS instead"
+        );
+    }
+
+    if configuration.refresh_lease() < configuration.min_refresh_lease() {
+        anyhow::bail!(
+            "REFRESH_LEASE_IN_SECONDS must be at least {} with TIMEOUT {} and MAX_RETRIES {}",
+            configuration.min_refresh_lease(),
+            configuration.timeout(),
+            configuration.max_retries()
+        );
+    }
+
     let state = AppState::try_from(configuration.clone()).await?;
 
-    let sleep_timer = Duration::from_secs(configuration.sleep_timer());
     let refresh_before = configuration.refresh_before();
+    let concurrency = configuration.refresh_concurrency();
+    let lease = Lease::new(state.instance_id(), configuration.refresh_lease());
+    let backoff = Backoff::new(
+        configuration.refresh_backoff_base(),
+        configuration.refresh_backoff_max(),
+    );
+    let msg = Refresh::new(
+        refresh_before,
+        configuration.reconcile_interval(),
+        concurrency,
+        state.refresh_permits().clone(),
+        lease,
+        backoff,
+    );
+
+    match command {
+        Command::Run => run(msg, configuration, state).await,
+        Command::Once { dry_run } => once(msg.with_dry_run(dry_run), state).await,
+        Command::Refresh { id, dry_run } => refresh_one(msg.with_dry_run(dry_run), id, state).await,
+        Command::Due { json } => due(msg, json, state).await,
+        Command::Revoke { id } => revoke(id, state).await,
+        Command::Help => Ok(ExitCode::SUCCESS),
+    }
+}
+
+/// Refreshes connections until SIGINT or SIGTERM.
+async fn run(
+    msg: Refresh,
+    configuration: RefreshConfig,
+    state: AppState,
+) -> anyhow::Result<ExitCode> {
+    // The scheduler would render the same due connections over and over, since
+    // nothing is written during a dry run.
+    if configuration.dry_run() {
+        tracing::warn!("DRY_RUN is set, running a single dry-run cycle instead");
+        return once(msg.with_dry_run(true), state).await;
+    }
+
+    let (shutdown, receiver) = tokio::sync::watch::channel(false);
+    let msg = msg.with_shutdown(Shutdown::new(receiver));
+
+    let catch_up = CatchUp::new(
+        configuration.catch_up_max_age(),
+        configuration.catch_up_limit(),
+    );
+    let catch_up_interval = Duration::from_secs(configuration.catch_up_interval());
+
+    let catching_up = tokio::spawn({
+        let msg = msg.clone().with_catch_up(catch_up);
+        let state = state.clone();
 
-    loop {
-        let res = refresh(
-            Refresh::new(refresh_before),
+        async move {
+            loop {
+                let res = refresh(
+                    msg.clone(),
+                    state.connections().clone(),
+                    state.secrets().clone(),
+                    state.oauths().clone(),
+                    state.client().clone(),
+                    state.metrics().clone(),
+                )
+                .await;
+                match res {
+                    Ok(_) => state.metrics().record_cycle_completed(),
+                    Err(e) => {
+                        tracing::warn!("Failed to catch up on expired connections: {:?}", e)
+                    }
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(catch_up_interval) => {}
+                    _ = msg.shutdown().triggered() => break,
+                }
+            }
+        }
+    });
+
+    let mut router = health_router(
+        state.clone(),
+        HealthThresholds {
+            stall_in_seconds: configuration.liveness_stall(),
+            cycle_age_in_seconds: configuration.readiness_cycle_age(),
+            check_timeout: Duration::from_secs(configuration.health_check_timeout()),
+        },
+    );
+    if configuration.admin_enabled() {
+        // The admin API can overwrite and revoke credentials, so it is never
+        // served without a token.
+        match configuration
+            .admin_token()
+            .filter(|token| !token.is_empty())
+        {
+            Some(token) => {
+                router = router.merge(admin_router(state.clone(), msg.clone(), token.to_string()));
+            }
+            None => tracing::error!("Admin API not served: ADMIN_ENABLED requires ADMIN_TOKEN"),
+        }
+    }
+    // Graceful shutdown lets in-flight requests finish, among them refreshes started
+    // through the admin API, so the server is awaited like the other tasks.
+    let serving = tokio::spawn({
+        let address = configuration.http_address().to_string();
+        let shutdown = msg.shutdown().clone();
+
+        async move {
+            if let Err(e) = serve(&address, router, shutdown).await {
+                tracing::error!("HTTP server stopped: {}", e);
+            }
+        }
+    });
+
+    let draining = tokio::spawn(drain_outbox(
+        msg.clone(),
+        state.secrets().clone(),
+        state.connections().clone(),
+        Duration::from_secs(configuration.outbox_interval()),
+        configuration.outbox_limit(),
+    ));
+
+    let sweeping = tokio::spawn({
+        let msg = msg.clone();
+        let state = state.clone();
+        let enabled = configuration.revocation_sweep_enabled();
+        let interval = Duration::from_secs(configuration.revocation_sweep_interval());
+        let limit = configuration.revocation_sweep_limit();
+
+        async move {
+            if !enabled {
+                return Ok(());
+            }
+
+            sweep_revocations(
+                msg,
+                state.secrets().clone(),
+                state.connections().clone(),
+                state.oauths().clone(),
+                state.client().clone(),
+                interval,
+                limit,
+            )
+            .await
+        }
+    });
+
+    let (sender, changes) = mpsc::channel(CHANGES_BUFFER);
+    if configuration.change_streams() {
+        tokio::spawn({
+            let oauths = state.oauths().clone();
+            async move { oauths.watch().await }
+        });
+
+        tokio::spawn(watch(
+            msg.clone(),
+            sender,
             state.connections().clone(),
-            state.secrets().clone(),
-            state.oauths().clone(),
-            state.client().clone(),
-            state.metrics().clone(),
-        )
-        .await;
-        if let Err(e) = res {
-            tracing::warn!("Failed to send refresh message: {:?}", e);
-        }
-
-        tracing::info!("Sleeping for {} seconds", sleep_timer.as_secs());
-        tokio::time::sleep(sleep_timer).await;
+            Duration::from_secs(configuration.change_streams_poll_interval()),
+        ));
+    }
+
+    let mut scheduling = tokio::spawn(schedule(
+        msg,
+        changes,
+        state.connections().clone(),
+        state.secrets().clone(),
+        state.oauths().clone(),
+        state.client().clone(),
+        state.metrics().clone(),
+    ));
+
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        res = &mut scheduling => {
+            res??;
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+
+    let deadline = Duration::from_secs(configuration.shutdown_deadline());
+    tracing::info!(
+        "Shutting down, waiting up to {} seconds for in-flight refreshes",
+        deadline.as_secs()
+    );
+    shutdown.send(true)?;
+
+    match tokio::time::timeout(deadline, async {
+        tokio::join!(scheduling, catching_up, draining, sweeping, serving)
+    })
+    .await
+    {
+        Ok(_) => {
+            tracing::info!("All in-flight refreshes finished");
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(_) => {
+            tracing::error!("In-flight refreshes did not finish before the shutdown deadline");
+            Ok(ExitCode::from(DEADLINE_EXCEEDED))
+        }
+    }
+}
+
+/// Runs a single refresh cycle and prints its summary.
+async fn once(msg: Refresh, state: AppState) -> anyhow::Result<ExitCode> {
+    let summary = refresh(
+        msg,
+        state.connections().clone(),
+        state.secrets().clone(),
+        state.oauths().clone(),
+        state.client().clone(),
+        state.metrics().clone(),
+    )
+    .await?;
+
+    println!("{}", serde_json::to_string(&summary)?);
+
+    Ok(if summary.failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Refreshes the connection `id` regardless of its expiry and prints the summary.
+async fn refresh_one(msg: Refresh, id: Id, state: AppState) -> anyhow::Result<ExitCode> {
+    let Some(connection) = state.connections().get(id).await? else {
+        eprintln!("Connection {id} not found");
+        return Ok(ExitCode::FAILURE);
+    };
+
+    let summary = refresh_connections(
+        &msg,
+        vec![connection],
+        state.connections().clone(),
+        state.secrets().clone(),
+        state.oauths().clone(),
+        state.client().clone(),
+        state.metrics().clone(),
+    )
+    .await?;
+
+    println!("{}", serde_json::to_string(&summary)?);
+
+    Ok(if summary.refreshed + summary.rendered.len() as u64 == 1 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Prints the connections the next refresh cycle would pick up.
+async fn due(msg: Refresh, json: bool, state: AppState) -> anyhow::Result<ExitCode> {
+    let connections = find_connections(&msg, state.connections(), state.oauths()).await?;
+
+    if json {
+        let connections = connections
+            .iter()
+            .map(DueConnection::from)
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&connections)?);
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let width = connections
+        .iter()
+        .map(|connection| connection.id.to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max("ID".len());
+    println!(
+        "{:<width$}  {:<20}  {:<12}  EXPIRES AT",
+        "ID", "PLATFORM", "ENVIRONMENT"
+    );
+    for connection in &connections {
+        let expires_at = expires_at(connection)
+            .and_then(|expires_at| DateTime::<Utc>::from_timestamp(expires_at, 0))
+            .map(|expires_at| expires_at.to_rfc3339())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<width$}  {:<20}  {:<12}  {}",
+            connection.id.to_string(),
+            connection.platform,
+            connection.environment.to_string(),
+            expires_at
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Revokes the tokens of the connection `id` and prints the outcome.
+async fn revoke(id: Id, state: AppState) -> anyhow::Result<ExitCode> {
+    let Some(connection) = state.connections().get(id).await? else {
+        eprintln!("Connection {id} not found");
+        return Ok(ExitCode::FAILURE);
+    };
+
+    match revoke_connection(
+        &connection,
+        state.secrets(),
+        state.connections(),
+        state.oauths(),
+        state.client(),
+    )
+    .await
+    {
+        Ok(outcome) => {
+            println!("{}", serde_json::to_string(&outcome)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(e) => {
+            eprintln!("Failed to revoke tokens of connection {id}: {e}");
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
     }
 }
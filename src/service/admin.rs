@@ -0,0 +1,256 @@
+use crate::{
+    exchange, find_connections, refresh_connections, revoke_connection, AppState, DueConnection,
+    Exchange, ExpiryPolicy, PkceChallenge, PkceMethod, Refresh, RefreshError, RefreshStatus,
+    RefreshSummary, RevocationOutcome, Shutdown, StorageExt, Unit,
+};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use osentities::{
+    error::PicaError as Error, ApplicationError, Connection, Id, InternalError, OAuth,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone)]
+struct AdminState {
+    app: AppState,
+    msg: Refresh,
+    token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Routes of the admin API. Every request has to carry `token` as a bearer token.
+pub fn admin_router(app: AppState, msg: Refresh, token: String) -> Router {
+    let state = AdminState { app, msg, token };
+
+    Router::new()
+        .route("/connections/:id/refresh", post(refresh))
+        .route("/connections/:id/refresh-status", get(refresh_status))
+        .route("/connections/:id/revoke", post(revoke))
+        .route("/due", get(due))
+        .route("/oauth/exchange", post(exchange_code))
+        .route("/oauth/pkce", post(pkce))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// Serves `router` on `address` until `shutdown` fires.
+pub async fn serve(address: &str, router: Router, shutdown: Shutdown) -> Result<Unit, Error> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .map_err(|e| InternalError::io_err(&format!("Failed to bind {address}: {e}"), None))?;
+
+    tracing::info!("Listening on {}", address);
+
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async move { shutdown.triggered().await })
+        .await
+        .map_err(|e| InternalError::io_err(&format!("Server on {address} failed: {e}"), None))
+}
+
+async fn authorize(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| constant_time_eq(bearer, &state.token));
+
+    if authorized {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Compares digests of both tokens so the time taken depends neither on where
+/// they first differ nor on their lengths.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let a = Sha256::digest(a.as_bytes());
+    let b = Sha256::digest(b.as_bytes());
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Refreshes one connection regardless of its expiry. Responds with `409` when
+/// another replica holds the connection or it was reauthorized meanwhile, and
+/// with `502` when the refresh failed.
+async fn refresh(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+    Query(params): Query<RefreshParams>,
+) -> Result<(StatusCode, Json<RefreshSummary>), Error> {
+    let connection = connection(&state.app, &id).await?;
+    let msg = state.msg.clone().with_dry_run(params.dry_run);
+
+    let summary = refresh_connections(
+        &msg,
+        vec![connection],
+        state.app.connections().clone(),
+        state.app.secrets().clone(),
+        state.app.oauths().clone(),
+        state.app.client().clone(),
+        state.app.metrics().clone(),
+    )
+    .await?;
+
+    let status = if summary.refreshed > 0 || !summary.rendered.is_empty() {
+        StatusCode::OK
+    } else if summary.failed > 0 {
+        StatusCode::BAD_GATEWAY
+    } else {
+        StatusCode::CONFLICT
+    };
+
+    Ok((status, Json(summary)))
+}
+
+/// Revokes the tokens of a deleted connection at its provider. Responds with `400`
+/// when the connection is live or the provider rejected the request, and with
+/// `502` when the provider failed.
+async fn revoke(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<Json<RevocationOutcome>, Response> {
+    let connection = connection(&state.app, &id)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let outcome = revoke_connection(
+        &connection,
+        state.app.secrets(),
+        state.app.connections(),
+        state.app.oauths(),
+        state.app.client(),
+    )
+    .await
+    .map_err(error_response)?;
+
+    Ok(Json(outcome))
+}
+
+async fn refresh_status(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<Json<RefreshStatus>, Error> {
+    let id: Id = id.parse()?;
+    let refresh_state = state
+        .app
+        .connections()
+        .get_refresh_state(&id)
+        .await?
+        .ok_or(ApplicationError::not_found(
+            &format!("Connection {id} not found"),
+            None,
+        ))?;
+
+    // The expiry policy is only needed for connections without an expiry.
+    let policy = match &refresh_state.oauth {
+        Some(OAuth::Enabled {
+            connection_oauth_definition_id,
+            expires_at: None,
+            ..
+        }) => state
+            .app
+            .oauths()
+            .get(connection_oauth_definition_id)
+            .await?
+            .map(|definition| definition.expiry_policy().clone())
+            .unwrap_or_default(),
+        _ => ExpiryPolicy::default(),
+    };
+
+    Ok(Json(RefreshStatus::new(
+        refresh_state,
+        state.msg.refresh_before_in_minutes(),
+        policy,
+    )))
+}
+
+/// Lists the connections the next refresh cycle picks up.
+async fn due(State(state): State<AdminState>) -> Result<Json<Vec<DueConnection>>, Error> {
+    let connections =
+        find_connections(&state.msg, state.app.connections(), state.app.oauths()).await?;
+
+    Ok(Json(connections.iter().map(DueConnection::from).collect()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PkceParams {
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    method: PkceMethod,
+}
+
+/// Starts a PKCE authorization request. The code verifier stays with the service
+/// and is sent with the code when it is exchanged under the returned state.
+async fn pkce(
+    State(state): State<AdminState>,
+    Json(params): Json<PkceParams>,
+) -> Result<Json<PkceChallenge>, Error> {
+    Ok(Json(
+        state.app.pkce().start(params.state, params.method).await?,
+    ))
+}
+
+/// Exchanges an authorization code for the tokens of a connection. Responds with
+/// `400` when the provider rejected the code and with `502` when it failed.
+async fn exchange_code(
+    State(state): State<AdminState>,
+    Json(msg): Json<Exchange>,
+) -> Result<Json<Connection>, Response> {
+    let connection = exchange(
+        msg,
+        state.app.secrets().clone(),
+        state.app.connections().clone(),
+        state.app.connection_definitions().clone(),
+        state.app.oauths().clone(),
+        state.app.pkce().clone(),
+        state.app.client().clone(),
+    )
+    .await
+    .map_err(error_response)?;
+
+    Ok(Json(connection))
+}
+
+/// Rejections by the provider are the caller's to fix, while other provider
+/// failures are reported as a bad gateway.
+fn error_response(e: RefreshError) -> Response {
+    match e {
+        RefreshError::OAuth(error) => (StatusCode::BAD_REQUEST, Json(error)).into_response(),
+        RefreshError::Status { .. } | RefreshError::Superseded | RefreshError::Validation(_) => {
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+        RefreshError::Internal(e) => e.into_response(),
+    }
+}
+
+async fn connection(app: &AppState, id: &str) -> Result<Connection, Error> {
+    let id: Id = id.parse()?;
+
+    app.connections()
+        .get(id)
+        .await?
+        .ok_or(ApplicationError::not_found(
+            &format!("Connection {id} not found"),
+            None,
+        ))
+}
@@ -2,16 +2,75 @@ use envconfig::Envconfig;
 use osentities::{database::DatabaseConfig, environment::Environment, secrets::SecretsConfig};
 use std::fmt::Debug;
 
+const DEFAULT_RECONCILE_INTERVAL_IN_SECONDS: u64 = 300;
+/// HTTP requests a single refresh attempt makes in the worst case: the provider,
+/// its JWKS endpoint, reading and writing the secret and validating the token.
+const REQUESTS_PER_REFRESH: i64 = 5;
+
 #[derive(Clone, Envconfig)]
 pub struct RefreshConfig {
     #[envconfig(from = "REFRESH_BEFORE_IN_MINUTES", default = "10")]
     refresh_before: i64,
-    #[envconfig(from = "SLEEP_TIMER_IN_SECONDS", default = "20")]
-    sleep_timer: u64,
+    #[envconfig(from = "RECONCILE_INTERVAL_IN_SECONDS")]
+    reconcile_interval: Option<u64>,
+    /// Deprecated name of `RECONCILE_INTERVAL_IN_SECONDS`, from when connections
+    /// were polled on a fixed interval.
+    #[envconfig(from = "SLEEP_TIMER_IN_SECONDS")]
+    sleep_timer: Option<u64>,
+    #[envconfig(from = "CHANGE_STREAMS_ENABLED", default = "true")]
+    change_streams: bool,
+    #[envconfig(from = "CHANGE_STREAMS_POLL_INTERVAL_IN_SECONDS", default = "20")]
+    change_streams_poll_interval: u64,
+    #[envconfig(from = "REFRESH_CONCURRENCY", default = "20")]
+    refresh_concurrency: usize,
+    #[envconfig(from = "REFRESH_LEASE_IN_SECONDS")]
+    refresh_lease: Option<i64>,
+    #[envconfig(from = "REFRESH_BACKOFF_BASE_IN_SECONDS", default = "60")]
+    refresh_backoff_base: i64,
+    #[envconfig(from = "REFRESH_BACKOFF_MAX_IN_SECONDS", default = "3600")]
+    refresh_backoff_max: i64,
+    #[envconfig(from = "CATCH_UP_INTERVAL_IN_SECONDS", default = "600")]
+    catch_up_interval: u64,
+    #[envconfig(from = "CATCH_UP_MAX_AGE_IN_MINUTES", default = "10080")]
+    catch_up_max_age: i64,
+    #[envconfig(from = "CATCH_UP_LIMIT", default = "100")]
+    catch_up_limit: u64,
     #[envconfig(nested = true)]
     database: DatabaseConfig,
     #[envconfig(nested = true)]
     secrets_config: SecretsConfig,
+    #[envconfig(from = "DEFINITION_CACHE_TTL_IN_SECONDS", default = "300")]
+    definition_cache_ttl: u64,
+    #[envconfig(from = "EVENT_ACCESS_CACHE_TTL_IN_SECONDS", default = "300")]
+    event_access_cache_ttl: u64,
+    #[envconfig(from = "OUTBOX_INTERVAL_IN_SECONDS", default = "30")]
+    outbox_interval: u64,
+    #[envconfig(from = "OUTBOX_LIMIT", default = "100")]
+    outbox_limit: i64,
+    #[envconfig(from = "ADMIN_ENABLED", default = "false")]
+    admin_enabled: bool,
+    #[envconfig(from = "HTTP_ADDRESS", default = "0.0.0.0:3007")]
+    http_address: String,
+    #[envconfig(from = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    #[envconfig(from = "LIVENESS_STALL_IN_SECONDS", default = "900")]
+    liveness_stall: i64,
+    #[envconfig(from = "READINESS_CYCLE_AGE_IN_SECONDS", default = "900")]
+    readiness_cycle_age: i64,
+    #[envconfig(from = "HEALTH_CHECK_TIMEOUT_IN_SECONDS", default = "5")]
+    health_check_timeout: u64,
+    #[envconfig(from = "PKCE_VERIFIER_TTL_IN_SECONDS", default = "600")]
+    pkce_verifier_ttl: i64,
+    #[envconfig(from = "REVOCATION_SWEEP_ENABLED", default = "false")]
+    revocation_sweep_enabled: bool,
+    #[envconfig(from = "REVOCATION_SWEEP_INTERVAL_IN_SECONDS", default = "300")]
+    revocation_sweep_interval: u64,
+    #[envconfig(from = "REVOCATION_SWEEP_LIMIT", default = "100")]
+    revocation_sweep_limit: u64,
+    #[envconfig(from = "DRY_RUN", default = "false")]
+    dry_run: bool,
+    #[envconfig(from = "SHUTDOWN_DEADLINE_IN_SECONDS", default = "30")]
+    shutdown_deadline: u64,
     #[envconfig(from = "TIMEOUT", default = "30")]
     timeout: u64,
     #[envconfig(from = "ENVIRONMENT", default = "test")]
@@ -23,6 +82,11 @@ pub struct RefreshConfig {
         default = "http://localhost:3005/v1/secrets"
     )]
     create_secret: String,
+    #[envconfig(
+        from = "DELETE_SECRET_PATH",
+        default = "http://localhost:3005/v1/secrets"
+    )]
+    delete_secret: String,
     #[envconfig(from = "MAX_RETRIES", default = "3")]
     max_retries: u32,
 }
@@ -30,11 +94,100 @@ pub struct RefreshConfig {
 impl Debug for RefreshConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "REFRESH_BEFORE_IN_MINUTES: {}", self.refresh_before)?;
-        writeln!(f, "SLEEP_TIMER_IN_SECONDS: {}", self.sleep_timer)?;
+        writeln!(
+            f,
+            "RECONCILE_INTERVAL_IN_SECONDS: {}",
+            self.reconcile_interval()
+        )?;
+        if let Some(sleep_timer) = self.sleep_timer {
+            writeln!(f, "SLEEP_TIMER_IN_SECONDS: {sleep_timer}")?;
+        }
+        writeln!(f, "CHANGE_STREAMS_ENABLED: {}", self.change_streams)?;
+        writeln!(
+            f,
+            "CHANGE_STREAMS_POLL_INTERVAL_IN_SECONDS: {}",
+            self.change_streams_poll_interval
+        )?;
+        writeln!(f, "REFRESH_CONCURRENCY: {}", self.refresh_concurrency)?;
+        writeln!(f, "REFRESH_LEASE_IN_SECONDS: {}", self.refresh_lease())?;
+        writeln!(
+            f,
+            "REFRESH_BACKOFF_BASE_IN_SECONDS: {}",
+            self.refresh_backoff_base
+        )?;
+        writeln!(
+            f,
+            "REFRESH_BACKOFF_MAX_IN_SECONDS: {}",
+            self.refresh_backoff_max
+        )?;
+        writeln!(
+            f,
+            "CATCH_UP_INTERVAL_IN_SECONDS: {}",
+            self.catch_up_interval
+        )?;
+        writeln!(f, "CATCH_UP_MAX_AGE_IN_MINUTES: {}", self.catch_up_max_age)?;
+        writeln!(f, "CATCH_UP_LIMIT: {}", self.catch_up_limit)?;
+        writeln!(
+            f,
+            "DEFINITION_CACHE_TTL_IN_SECONDS: {}",
+            self.definition_cache_ttl
+        )?;
+        writeln!(
+            f,
+            "EVENT_ACCESS_CACHE_TTL_IN_SECONDS: {}",
+            self.event_access_cache_ttl
+        )?;
+        writeln!(f, "OUTBOX_INTERVAL_IN_SECONDS: {}", self.outbox_interval)?;
+        writeln!(f, "OUTBOX_LIMIT: {}", self.outbox_limit)?;
+        writeln!(f, "ADMIN_ENABLED: {}", self.admin_enabled)?;
+        writeln!(f, "HTTP_ADDRESS: {}", self.http_address)?;
+        writeln!(
+            f,
+            "ADMIN_TOKEN: {}",
+            if self.admin_token.is_some() {
+                "***"
+            } else {
+                ""
+            }
+        )?;
+        writeln!(f, "LIVENESS_STALL_IN_SECONDS: {}", self.liveness_stall)?;
+        writeln!(
+            f,
+            "READINESS_CYCLE_AGE_IN_SECONDS: {}",
+            self.readiness_cycle_age
+        )?;
+        writeln!(
+            f,
+            "HEALTH_CHECK_TIMEOUT_IN_SECONDS: {}",
+            self.health_check_timeout
+        )?;
+        writeln!(
+            f,
+            "PKCE_VERIFIER_TTL_IN_SECONDS: {}",
+            self.pkce_verifier_ttl
+        )?;
+        writeln!(
+            f,
+            "REVOCATION_SWEEP_ENABLED: {}",
+            self.revocation_sweep_enabled
+        )?;
+        writeln!(
+            f,
+            "REVOCATION_SWEEP_INTERVAL_IN_SECONDS: {}",
+            self.revocation_sweep_interval
+        )?;
+        writeln!(f, "REVOCATION_SWEEP_LIMIT: {}", self.revocation_sweep_limit)?;
+        writeln!(f, "DRY_RUN: {}", self.dry_run)?;
+        writeln!(
+            f,
+            "SHUTDOWN_DEADLINE_IN_SECONDS: {}",
+            self.shutdown_deadline
+        )?;
         writeln!(f, "TIMEOUT: {}", self.timeout)?;
         writeln!(f, "ENVIRONMENT: {}", self.environment)?;
         writeln!(f, "GET_SECRET_PATH: {}", self.get_secret)?;
         writeln!(f, "CREATE_SECRET_PATH: {}", self.create_secret)?;
+        writeln!(f, "DELETE_SECRET_PATH: {}", self.delete_secret)?;
         writeln!(f, "MAX_RETRIES: {}", self.max_retries)?;
         write!(f, "{}", self.database)?;
         write!(f, "{}", self.secrets_config)
@@ -46,10 +199,63 @@ impl RefreshConfig {
         self.refresh_before
     }
 
-    pub fn sleep_timer(&self) -> u64 {
+    /// Falls back to the deprecated `SLEEP_TIMER_IN_SECONDS` when
+    /// `RECONCILE_INTERVAL_IN_SECONDS` is not set.
+    pub fn reconcile_interval(&self) -> u64 {
+        self.reconcile_interval
+            .or(self.sleep_timer)
+            .unwrap_or(DEFAULT_RECONCILE_INTERVAL_IN_SECONDS)
+    }
+
+    pub fn sleep_timer(&self) -> Option<u64> {
         self.sleep_timer
     }
 
+    pub fn change_streams(&self) -> bool {
+        self.change_streams
+    }
+
+    pub fn change_streams_poll_interval(&self) -> u64 {
+        self.change_streams_poll_interval
+    }
+
+    pub fn refresh_concurrency(&self) -> usize {
+        self.refresh_concurrency.max(1)
+    }
+
+    /// Defaults to the longest a refresh attempt can take, see `min_refresh_lease`.
+    pub fn refresh_lease(&self) -> i64 {
+        self.refresh_lease
+            .unwrap_or_else(|| self.min_refresh_lease())
+    }
+
+    /// Longest a refresh attempt can take, with every request timing out after
+    /// `TIMEOUT` on each of its `MAX_RETRIES` retries. A shorter lease would let
+    /// another replica claim the connection while the attempt is still running.
+    pub fn min_refresh_lease(&self) -> i64 {
+        REQUESTS_PER_REFRESH * self.timeout as i64 * (1 + i64::from(self.max_retries))
+    }
+
+    pub fn refresh_backoff_base(&self) -> i64 {
+        self.refresh_backoff_base
+    }
+
+    pub fn refresh_backoff_max(&self) -> i64 {
+        self.refresh_backoff_max
+    }
+
+    pub fn catch_up_interval(&self) -> u64 {
+        self.catch_up_interval
+    }
+
+    pub fn catch_up_max_age(&self) -> i64 {
+        self.catch_up_max_age
+    }
+
+    pub fn catch_up_limit(&self) -> u64 {
+        self.catch_up_limit
+    }
+
     pub fn database(&self) -> &DatabaseConfig {
         &self.database
     }
@@ -58,6 +264,70 @@ impl RefreshConfig {
         &self.secrets_config
     }
 
+    pub fn definition_cache_ttl(&self) -> u64 {
+        self.definition_cache_ttl
+    }
+
+    pub fn event_access_cache_ttl(&self) -> u64 {
+        self.event_access_cache_ttl
+    }
+
+    pub fn outbox_interval(&self) -> u64 {
+        self.outbox_interval
+    }
+
+    pub fn outbox_limit(&self) -> i64 {
+        self.outbox_limit
+    }
+
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_enabled
+    }
+
+    pub fn http_address(&self) -> &str {
+        &self.http_address
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    pub fn liveness_stall(&self) -> i64 {
+        self.liveness_stall
+    }
+
+    pub fn readiness_cycle_age(&self) -> i64 {
+        self.readiness_cycle_age
+    }
+
+    pub fn health_check_timeout(&self) -> u64 {
+        self.health_check_timeout
+    }
+
+    pub fn pkce_verifier_ttl(&self) -> i64 {
+        self.pkce_verifier_ttl
+    }
+
+    pub fn revocation_sweep_enabled(&self) -> bool {
+        self.revocation_sweep_enabled
+    }
+
+    pub fn revocation_sweep_interval(&self) -> u64 {
+        self.revocation_sweep_interval
+    }
+
+    pub fn revocation_sweep_limit(&self) -> u64 {
+        self.revocation_sweep_limit
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn shutdown_deadline(&self) -> u64 {
+        self.shutdown_deadline
+    }
+
     pub fn timeout(&self) -> u64 {
         self.timeout
     }
@@ -74,6 +344,10 @@ impl RefreshConfig {
         &self.create_secret
     }
 
+    pub fn delete_secret(&self) -> &str {
+        &self.delete_secret
+    }
+
     pub fn max_retries(&self) -> u32 {
         self.max_retries
     }
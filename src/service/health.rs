@@ -0,0 +1,110 @@
+use crate::AppState;
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use chrono::Utc;
+use mongodb::bson::doc;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Limits past which the process is reported as not ready or not live.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthThresholds {
+    /// Longest the refresh loop may go without making progress before liveness fails.
+    pub stall_in_seconds: i64,
+    /// Oldest the last completed cycle may be for the process to be ready.
+    pub cycle_age_in_seconds: i64,
+    /// Time allowed to each dependency check.
+    pub check_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct HealthState {
+    app: AppState,
+    thresholds: HealthThresholds,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Liveness {
+    live: bool,
+    last_heartbeat_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    ready: bool,
+    mongo: bool,
+    secrets_service: bool,
+    last_cycle_at: Option<i64>,
+}
+
+/// `/healthz` and `/readyz`. Both are left out of admin authorization so the
+/// orchestrator can probe them.
+pub fn health_router(app: AppState, thresholds: HealthThresholds) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(HealthState { app, thresholds })
+}
+
+/// Fails once the refresh loop has stalled, so the process gets restarted.
+async fn healthz(State(state): State<HealthState>) -> (StatusCode, Json<Liveness>) {
+    let last_heartbeat_at = state.app.metrics().last_heartbeat_at();
+    let live = Utc::now().timestamp() - last_heartbeat_at <= state.thresholds.stall_in_seconds;
+
+    (
+        status(live),
+        Json(Liveness {
+            live,
+            last_heartbeat_at,
+        }),
+    )
+}
+
+/// Ready while Mongo and the secrets service answer and a cycle completed recently.
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, Json<Readiness>) {
+    let timeout = state.thresholds.check_timeout;
+
+    let (mongo, secrets_service) = tokio::join!(
+        tokio::time::timeout(
+            timeout,
+            state.app.database().run_command(doc! { "ping": 1 })
+        ),
+        tokio::time::timeout(timeout, state.app.secrets().is_reachable()),
+    );
+    let mongo = matches!(mongo, Ok(Ok(_)));
+    let secrets_service = secrets_service.unwrap_or(false);
+
+    let last_cycle_at = Some(state.app.metrics().last_cycle_at()).filter(|at| *at > 0);
+    let recent_cycle = last_cycle_at.is_some_and(|last_cycle_at| {
+        Utc::now().timestamp() - last_cycle_at <= state.thresholds.cycle_age_in_seconds
+    });
+
+    let ready = mongo && secrets_service && recent_cycle;
+    if !ready {
+        tracing::warn!(
+            "Not ready: mongo {}, secrets service {}, last cycle at {:?}",
+            mongo,
+            secrets_service,
+            last_cycle_at
+        );
+    }
+
+    (
+        status(ready),
+        Json(Readiness {
+            ready,
+            mongo,
+            secrets_service,
+            last_cycle_at,
+        }),
+    )
+}
+
+fn status(healthy: bool) -> StatusCode {
+    if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -1,28 +1,42 @@
+mod admin;
 mod configuration;
+mod health;
 
+pub use admin::*;
 pub use configuration::*;
+pub use health::*;
 
-use crate::{Metrics, SecretsClient};
-use mongodb::{bson::doc, options::FindOptions};
+use crate::{Backoff, DefinitionCache, Metrics, Outbox, PkceStore, SecretsClient};
+use mongodb::{bson::doc, options::FindOptions, Database};
 use osentities::{
-    algebra::MongoStore, connection_oauth_definition::ConnectionOAuthDefinition,
-    error::PicaError as Error, event_access::EventAccess, Connection, InternalError, Store,
+    algebra::{IOSCrypto, MongoStore},
+    connection_definition::ConnectionDefinition,
+    connection_oauth_definition::ConnectionOAuthDefinition,
+    error::PicaError as Error,
+    event_access::EventAccess,
+    Connection, InternalError, Store,
 };
 use reqwest::Client;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde_json::Value;
 use std::{sync::Arc, time::Duration};
-use tokio::time::timeout;
+use tokio::{sync::Semaphore, time::timeout};
+use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub struct AppState {
     client: ClientWithMiddleware,
     secrets: Arc<SecretsClient>,
     connections: Arc<MongoStore<Connection>>,
-    oauths: Arc<MongoStore<ConnectionOAuthDefinition>>,
+    connection_definitions: Arc<MongoStore<ConnectionDefinition>>,
+    oauths: Arc<DefinitionCache>,
     event_access: Arc<MongoStore<EventAccess>>,
     metrics: Arc<Metrics>,
+    pkce: Arc<PkceStore>,
+    refresh_permits: Arc<Semaphore>,
+    database: Database,
+    instance_id: String,
 }
 
 impl AppState {
@@ -62,29 +76,49 @@ impl AppState {
                 })
         })
         .await
-        .unwrap_or_else(|_| panic!("Failed to connect to MongoDB within {} seconds. Please check your connection string.", config.timeout()))
+        .map_err(|_| {
+            // Startup carries on so the readiness endpoint can report the outage.
+            tracing::warn!("Failed to connect to MongoDB within {} seconds. Please check your connection string.", config.timeout());
+        })
         .ok();
 
         let oauths =
             MongoStore::<ConnectionOAuthDefinition>::new(&db, &Store::ConnectionOAuthDefinitions)
                 .await?;
         let connections = MongoStore::<Connection>::new(&db, &Store::Connections).await?;
+        let connection_definitions =
+            MongoStore::<ConnectionDefinition>::new(&db, &Store::ConnectionDefinitions).await?;
         let event_access = MongoStore::<EventAccess>::new(&db, &Store::EventAccess).await?;
 
-        let oauths = Arc::new(oauths);
+        let oauths = Arc::new(DefinitionCache::new(
+            Arc::new(oauths),
+            Duration::from_secs(config.definition_cache_ttl()),
+        ));
         let connections = Arc::new(connections);
+        let connection_definitions = Arc::new(connection_definitions);
         let event_access = Arc::new(event_access);
         let metrics = Arc::new(Metrics::new()?);
-        let secrets = SecretsClient::new(&config, &event_access, client.clone());
+        let outbox = Outbox::new(
+            &db,
+            IOSCrypto::new(config.secrets_config().clone())?,
+            Backoff::new(config.refresh_backoff_base(), config.refresh_backoff_max()),
+        );
+        let secrets = SecretsClient::new(&config, &event_access, client.clone(), outbox);
         let secrets = Arc::new(secrets);
+        let pkce = Arc::new(PkceStore::new(&db, config.pkce_verifier_ttl()));
 
         Ok(AppState {
             event_access,
             connections,
+            connection_definitions,
             metrics,
             client,
             oauths,
             secrets,
+            pkce,
+            refresh_permits: Arc::new(Semaphore::new(config.refresh_concurrency())),
+            database: db,
+            instance_id: format!("oauth-refresh-{}", Uuid::new_v4()),
         })
     }
 
@@ -96,7 +130,11 @@ impl AppState {
         &self.connections
     }
 
-    pub fn oauths(&self) -> &Arc<MongoStore<ConnectionOAuthDefinition>> {
+    pub fn connection_definitions(&self) -> &Arc<MongoStore<ConnectionDefinition>> {
+        &self.connection_definitions
+    }
+
+    pub fn oauths(&self) -> &Arc<DefinitionCache> {
         &self.oauths
     }
 
@@ -111,4 +149,22 @@ impl AppState {
     pub fn metrics(&self) -> &Arc<Metrics> {
         &self.metrics
     }
+
+    pub fn pkce(&self) -> &Arc<PkceStore> {
+        &self.pkce
+    }
+
+    /// Bounds the refreshes running at once across the scheduler, the catch-up
+    /// sweep and the admin endpoint to `REFRESH_CONCURRENCY`.
+    pub fn refresh_permits(&self) -> &Arc<Semaphore> {
+        &self.refresh_permits
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
 }